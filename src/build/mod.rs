@@ -1,35 +1,197 @@
 use crate::{
-	docker::{remove_container, run_container_to_completion},
-	Config
+	config::LocalFile,
+	docker::{build_image, remove_container, run_container_to_completion, tar_header},
+	repo, Config
 };
+use anyhow::Context;
+use askama::Template;
 use bollard::{
 	container,
+	image::BuildImageOptions,
 	models::{HostConfig, Mount, MountTypeEnum},
 	Docker
 };
-use std::{collections::HashMap, io, path::Path, process::exit};
+use sha2::{Digest, Sha512};
+use std::{
+	collections::HashMap,
+	io::{self, Cursor},
+	path::Path
+};
 use tokio::fs;
 
 pub mod packages;
 pub mod rust;
 
-async fn up_to_date(repodir: &Path, config: &Config, pkgname: &str, pkgver: &str, pkgrel: u32) -> bool {
-	let path = format!(
-		"{}/alpine-rust/x86_64/{}-{}-r{}.apk",
-		config.alpine.version, pkgname, pkgver, pkgrel
-	);
-	info!("Checking if {} is up to date ...", path);
-	match fs::metadata(repodir.join(path)).await {
-		Ok(_) => true,                                              // file exists
-		Err(err) if err.kind() == io::ErrorKind::NotFound => false, // not found
-		Err(err) => {
-			error!("Unable to check if package was up to date: {}", err);
-			// exiting is fine because no upcloud server was provisioned yet
-			exit(1);
+/// Return the key of the .apk file of a package inside the repository.
+fn apk_key(config: &Config, pkgname: &str, pkgver: &str, pkgrel: u32) -> String {
+	format!("{}/{}-{}-r{}.apk", config.alpine.repo_dir(), pkgname, pkgver, pkgrel)
+}
+
+/// Append a suffix to a docker tag, replacing the tag if it is `latest`.
+fn suffix_tag(tag: &str, suffix: &str) -> String {
+	match tag {
+		"latest" => suffix.to_owned(),
+		tag => format!("{}-{}", tag, suffix)
+	}
+}
+
+/// Return the docker tags of an image for the Alpine release and architecture of the config.
+/// Every tag is suffixed with the release, and the images of the default release additionally get
+/// the plain tag. Images of any architecture but the default one are further suffixed with the
+/// architecture.
+fn docker_tags(config: &Config, image: &str, tag: &str) -> Vec<String> {
+	let mut tags = Vec::new();
+	if config.alpine.is_default() {
+		tags.push(tag.to_owned());
+	}
+	tags.push(suffix_tag(tag, &format!("alpine{}", config.alpine.version)));
+	if !config.alpine.is_default_arch() {
+		tags = tags.iter().map(|tag| suffix_tag(tag, &config.alpine.arch)).collect();
+	}
+	tags.into_iter().map(|tag| format!("{}:{}", image, tag)).collect()
+}
+
+/// Return the name of the image that builds or tests a package for the Alpine release and
+/// architecture of the config.
+fn local_image(config: &Config, purpose: &str, pkgname: &str) -> String {
+	format!(
+		"alpine-rust-{}-{}-{}-{}",
+		purpose, config.alpine.version, config.alpine.arch, pkgname
+	)
+}
+
+/// How the private key that signs the packages is added to a build context.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrivKey {
+	Include,
+	Placeholder,
+	Omit
+}
+
+const PRIVKEY_PLACEHOLDER: &str = "Replace this file with the private key that should be used to sign the packages.\n";
+
+/// The files that are sent to docker as the context of an image build.
+#[derive(Debug, Default)]
+pub struct BuildContext {
+	files: Vec<(String, Vec<u8>)>
+}
+
+impl BuildContext {
+	fn add(&mut self, name: &str, bytes: impl Into<Vec<u8>>) {
+		self.files.push((name.to_owned(), bytes.into()));
+	}
+
+	/// Add a file from the host, using its path as the name inside the context.
+	async fn add_file(&mut self, path: &str) -> anyhow::Result<()> {
+		let bytes = fs::read(path).await.with_context(|| format!("Unable to read {}", path))?;
+		self.add(path, bytes);
+		Ok(())
+	}
+
+	async fn add_privkey(&mut self, privkey: &str, mode: PrivKey) -> anyhow::Result<()> {
+		match mode {
+			PrivKey::Include => self.add_file(privkey).await?,
+			PrivKey::Placeholder => self.add(privkey, PRIVKEY_PLACEHOLDER),
+			PrivKey::Omit => {}
+		}
+		Ok(())
+	}
+
+	/// Create the tar archive that is sent to docker.
+	fn to_tar(&self) -> anyhow::Result<Vec<u8>> {
+		let mut tar_buf: Vec<u8> = Vec::new();
+		let mut tar = tar::Builder::new(&mut tar_buf);
+		for (name, bytes) in &self.files {
+			let header = tar_header(name, bytes.len());
+			tar.append(&header, Cursor::new(bytes))?;
+		}
+
+		// finish the tar archive
+		tar.finish()?;
+		drop(tar);
+		Ok(tar_buf)
+	}
+
+	/// Write all files of the context into a directory.
+	pub async fn write_to(&self, dir: &Path) -> anyhow::Result<()> {
+		for (name, bytes) in &self.files {
+			let path = dir.join(name);
+			if let Some(parent) = path.parent() {
+				fs::create_dir_all(parent)
+					.await
+					.with_context(|| format!("Unable to create {}", parent.display()))?;
+			}
+			fs::write(&path, bytes)
+				.await
+				.with_context(|| format!("Unable to write {}", path.display()))?;
 		}
+		Ok(())
 	}
 }
 
+/// Return the key of the file next to the .apk file that stores the hash of the APKBUILD that the
+/// package was built from.
+fn apkbuild_hash_key(apk_key: &str) -> String {
+	format!("{}.APKBUILD.sha512", apk_key.trim_end_matches(".apk"))
+}
+
+/// Hash a rendered APKBUILD and the files next to it. The pkgrel is ignored, so that bumping it does
+/// not change the hash.
+pub fn apkbuild_hash(apkbuild: &str, files: &[LocalFile]) -> String {
+	let mut hash = Sha512::new();
+	for line in apkbuild.lines().filter(|line| !line.starts_with("pkgrel=")) {
+		hash.update(line.as_bytes());
+		hash.update(b"\n");
+	}
+	for file in files {
+		hash.update(format!("{}  {}\n", file.sha512sum, file.name).as_bytes());
+	}
+	format!("{:x}", hash.finalize())
+}
+
+/// Return the hash of the APKBUILD that the package in the repodir was built from, or `None` if it
+/// was built before the hashes were recorded.
+pub async fn read_apkbuild_hash(repodir: &Path, apk_key: &str) -> anyhow::Result<Option<String>> {
+	let path = repodir.join(apkbuild_hash_key(apk_key));
+	match fs::read_to_string(&path).await {
+		Ok(hash) => Ok(Some(hash.trim().to_owned())),
+		Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(err).with_context(|| format!("Unable to read {}", path.display()))
+	}
+}
+
+/// Record the hash of the APKBUILD that the package in the repodir was built from. It is uploaded
+/// together with the package.
+pub async fn write_apkbuild_hash(repodir: &Path, apk_key: &str, hash: &str) -> anyhow::Result<()> {
+	let path = repodir.join(apkbuild_hash_key(apk_key));
+	fs::write(&path, format!("{}\n", hash))
+		.await
+		.with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// Return whether the package exists in the repodir or is listed in the index of the repodir,
+/// since the packages themselves are only downloaded when they are needed.
+pub async fn up_to_date(repodir: &Path, key: &str) -> anyhow::Result<bool> {
+	info!("Checking if {} is up to date ...", key);
+	match fs::metadata(repodir.join(key)).await {
+		Ok(_) => return Ok(true),                                // file exists
+		Err(err) if err.kind() == io::ErrorKind::NotFound => {}, // check the index
+		Err(err) => return Err(err).context("Unable to check if package was up to date")
+	}
+
+	let key = Path::new(key);
+	let (dir, file_name) = match (key.parent(), key.file_name()) {
+		(Some(dir), Some(file_name)) => (dir, file_name),
+		_ => return Ok(false)
+	};
+	let index_path = repodir.join(dir).join("APKINDEX.tar.gz");
+	if !index_path.exists() {
+		return Ok(false);
+	}
+	let index = repo::read_index(&index_path).context("Unable to read the repository index")?;
+	Ok(index.iter().any(|entry| file_name == entry.file_name().as_str()))
+}
+
 async fn docker_run_abuild(docker: &Docker, img: &str, repomount: &str) -> anyhow::Result<()> {
 	info!("Creating container for {}", img);
 
@@ -62,3 +224,57 @@ async fn docker_run_abuild(docker: &Docker, img: &str, repomount: &str) -> anyho
 	run_container_to_completion(docker, &container.id).await?;
 	remove_container(docker, &container.id).await
 }
+
+/// Rebuild and sign the index of the repository of the Alpine release and architecture of the
+/// config from the packages that are present, e.g. after packages were deleted. If `merge` is set,
+/// the packages of the remote index that were never downloaded are kept, see
+/// [repo::download_index].
+pub async fn rebuild_index(repomount: &str, docker: &Docker, config: &Config, merge: bool) -> anyhow::Result<()> {
+	let img = local_image(config, "index", "alpine-rust");
+	info!("Building Docker image {}", img);
+
+	let mut context = BuildContext::default();
+	context.add("Dockerfile", config.index_dockerfile().render()?);
+	context.add("index.sh", config.index_script(merge).render()?);
+	context.add_privkey(&config.alpine.privkey, PrivKey::Include).await?;
+	build_image(
+		docker,
+		BuildImageOptions {
+			t: img.as_str(),
+			pull: true,
+			..Default::default()
+		},
+		context.to_tar()?
+	)
+	.await?;
+	info!("Built Docker image {}", img);
+
+	docker_run_abuild(docker, &img, repomount).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn apkbuild_hash_ignores_pkgrel() {
+		let apkbuild = "pkgname=rust\npkgver=1.50.0\npkgrel=0\nsource=\"rustc-$pkgver-src.tar.gz\"\n";
+		let bumped = apkbuild.replace("pkgrel=0", "pkgrel=1");
+		assert_eq!(apkbuild_hash(apkbuild, &[]), apkbuild_hash(&bumped, &[]));
+
+		let changed = apkbuild.replace("pkgver=1.50.0", "pkgver=1.51.0");
+		assert_ne!(apkbuild_hash(apkbuild, &[]), apkbuild_hash(&changed, &[]));
+	}
+
+	#[test]
+	fn apkbuild_hash_includes_files() {
+		let apkbuild = "pkgname=foo\npkgver=1.0\npkgrel=0\n";
+		let file = |content: &str| LocalFile {
+			name: "fix.patch".to_owned(),
+			content: content.as_bytes().to_owned(),
+			sha512sum: format!("{:x}", Sha512::digest(content.as_bytes()))
+		};
+		assert_ne!(apkbuild_hash(apkbuild, &[]), apkbuild_hash(apkbuild, &[file("a")]));
+		assert_ne!(apkbuild_hash(apkbuild, &[file("a")]), apkbuild_hash(apkbuild, &[file("b")]));
+	}
+}
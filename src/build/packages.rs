@@ -1,15 +1,11 @@
-use super::docker_run_abuild;
+use super::{docker_run_abuild, local_image, BuildContext, PrivKey};
 use crate::{
-	config::{Config, PackageCrate, PackageLLVM},
-	docker::{build_image, docker_push, tar_header}
+	config::{Config, LocalFile, PackageCrate, PackageCustom, PackageLLVM},
+	docker::{build_image, tag_image}
 };
 use askama::Template;
-use bollard::{
-	image::{BuildImageOptions, TagImageOptions},
-	Docker
-};
-use std::{fmt::Debug, io::Cursor, path::Path};
-use tokio::{fs::File, io::AsyncReadExt};
+use bollard::{image::BuildImageOptions, Docker};
+use std::fmt::Debug;
 
 pub trait Package: Debug + Send + Sync {
 	fn pkgname(&self) -> String;
@@ -18,6 +14,11 @@ pub trait Package: Debug + Send + Sync {
 
 	fn render_apkbuild(&self, config: &Config) -> Result<String, askama::Error>;
 	fn render_dockerfile(&self, config: &Config) -> Option<Result<String, askama::Error>>;
+
+	/// The files that are copied next to the APKBUILD.
+	fn files(&self) -> &[LocalFile] {
+		&[]
+	}
 }
 
 impl Package for PackageLLVM {
@@ -58,51 +59,94 @@ impl Package for PackageCrate {
 	}
 }
 
-pub async fn up_to_date(repodir: &Path, config: &Config, pkg: &dyn Package) -> bool {
-	super::up_to_date(repodir, config, &pkg.pkgname(), pkg.pkgver(), pkg.pkgrel()).await
+impl Package for PackageCustom {
+	fn pkgname(&self) -> String {
+		self.pkgname.clone()
+	}
+	fn pkgver(&self) -> &str {
+		&self.pkgver
+	}
+	fn pkgrel(&self) -> u32 {
+		self.pkgrel
+	}
+
+	fn render_apkbuild(&self, config: &Config) -> Result<String, askama::Error> {
+		config.package_custom_apkbuild(self).render()
+	}
+	fn render_dockerfile(&self, config: &Config) -> Option<Result<String, askama::Error>> {
+		config.package_custom_dockerfile(self).map(|dockerfile| dockerfile.render())
+	}
+
+	fn files(&self) -> &[LocalFile] {
+		&self.local_files
+	}
+}
+
+pub fn apk_key(config: &Config, pkg: &dyn Package) -> String {
+	super::apk_key(config, &pkg.pkgname(), pkg.pkgver(), pkg.pkgrel())
+}
+
+fn docker_image(config: &Config, pkg: &dyn Package) -> String {
+	config.deploy.registry.image(&format!("alpine-{}", pkg.pkgname()))
+}
+
+/// Return the docker tags that will be pushed for this package.
+pub fn docker_tags(config: &Config, pkg: &dyn Package) -> Vec<String> {
+	if pkg.render_dockerfile(config).is_none() {
+		return Vec::new();
+	}
+	let image = docker_image(config, pkg);
+	let mut tags = super::docker_tags(config, &image, pkg.pkgver());
+	tags.extend(super::docker_tags(config, &image, "latest"));
+	tags
 }
 
-async fn build_tar(
+async fn build_context(
 	apkbuild: Option<&str>,
 	dockerfile: &str,
-	pubkey: &str,
-	privkey: Option<&str>
-) -> anyhow::Result<Vec<u8>> {
-	let mut tar_buf: Vec<u8> = Vec::new();
-	let mut tar = tar::Builder::new(&mut tar_buf);
+	config: &Config,
+	privkey: PrivKey
+) -> anyhow::Result<BuildContext> {
+	let mut context = BuildContext::default();
 
 	// write the APKBUILD file
 	if let Some(apkbuild) = apkbuild {
-		let bytes = apkbuild.as_bytes();
-		let header = tar_header("APKBUILD", bytes.len());
-		tar.append(&header, Cursor::new(bytes))?;
+		context.add("APKBUILD", apkbuild);
 	}
 
 	// write the Dockerfile file
-	let bytes = dockerfile.as_bytes();
-	let header = tar_header("Dockerfile", bytes.len());
-	tar.append(&header, Cursor::new(bytes))?;
-
-	// copy the public key
-	let mut file = File::open(pubkey).await?;
-	let mut bytes = Vec::<u8>::new();
-	file.read_to_end(&mut bytes).await?;
-	let header = tar_header(pubkey, bytes.len());
-	tar.append(&header, Cursor::new(bytes))?;
-
-	// copy the private key
-	if let Some(privkey) = privkey {
-		let mut file = File::open(privkey).await?;
-		let mut bytes = Vec::<u8>::new();
-		file.read_to_end(&mut bytes).await?;
-		let header = tar_header(privkey, bytes.len());
-		tar.append(&header, Cursor::new(bytes))?;
-	}
-
-	// finish the tar archive
-	tar.finish()?;
-	drop(tar);
-	Ok(tar_buf)
+	context.add("Dockerfile", dockerfile);
+
+	// copy the keys
+	context.add_file(&config.alpine.pubkey).await?;
+	context.add_privkey(&config.alpine.privkey, privkey).await?;
+
+	Ok(context)
+}
+
+/// Return the build context of the image that builds the package.
+pub async fn abuild_context(
+	config: &Config,
+	pkg: &dyn Package,
+	jobs: u16,
+	privkey: PrivKey
+) -> anyhow::Result<BuildContext> {
+	let apkbuild: String = pkg.render_apkbuild(config)?;
+	let dockerfile = config.packages_dockerfile_abuild(jobs, pkg.files()).render()?;
+	let mut context = build_context(Some(&apkbuild), &dockerfile, config, privkey).await?;
+	for file in pkg.files() {
+		context.add(&file.name, file.content.as_slice());
+	}
+	Ok(context)
+}
+
+/// Return the build context of the docker image of the package, if it has one.
+pub async fn image_context(config: &Config, pkg: &dyn Package) -> anyhow::Result<Option<BuildContext>> {
+	let dockerfile = match pkg.render_dockerfile(config) {
+		Some(dockerfile) => dockerfile?,
+		None => return Ok(None)
+	};
+	Ok(Some(build_context(None, &dockerfile, config, PrivKey::Omit).await?))
 }
 
 async fn docker_build_abuild(
@@ -115,15 +159,7 @@ async fn docker_build_abuild(
 	info!("Building Docker image {}", tag);
 
 	// create the context tar for docker build
-	let apkbuild: String = pkg.render_apkbuild(config)?;
-	let dockerfile = config.packages_dockerfile_abuild(jobs).render()?;
-	let tar = build_tar(
-		Some(&apkbuild),
-		&dockerfile,
-		&config.alpine.pubkey,
-		Some(&config.alpine.privkey)
-	)
-	.await?;
+	let tar = abuild_context(config, pkg, jobs, PrivKey::Include).await?.to_tar()?;
 
 	// build the docker image
 	build_image(
@@ -131,6 +167,7 @@ async fn docker_build_abuild(
 		BuildImageOptions {
 			t: tag.to_owned(),
 			pull: true,
+			platform: config.alpine.docker_platform().to_owned(),
 			..Default::default()
 		},
 		tar
@@ -149,57 +186,40 @@ pub async fn build_package(
 ) -> anyhow::Result<()> {
 	info!("Building Package {}", pkg.pkgname());
 
-	let img = format!("alpine-rust-builder-{}", pkg.pkgname());
+	let img = local_image(config, "builder", &pkg.pkgname());
 	docker_build_abuild(docker, &img, config, pkg, jobs).await?;
 	docker_run_abuild(docker, &img, repomount).await?;
 
 	Ok(())
 }
 
-pub async fn build_and_upload_docker(
-	docker: &Docker,
-	config: &Config,
-	pkg: &dyn Package,
-	upload_docker: bool
-) -> anyhow::Result<()> {
-	let dockerfile = match pkg.render_dockerfile(config) {
-		Some(dockerfile) => dockerfile?,
+/// Build the docker image of the package, if it has one. Use [docker_tags] to push it.
+pub async fn build_docker(docker: &Docker, config: &Config, pkg: &dyn Package) -> anyhow::Result<()> {
+	let context = match image_context(config, pkg).await? {
+		Some(context) => context,
 		None => {
 			debug!("No Dockerfile specified for package {:?}", pkg);
 			return Ok(());
 		}
 	};
 
-	let pkgname = pkg.pkgname();
-	let pkgver = pkg.pkgver();
-	let image = format!("ghcr.io/msrd0/alpine-{}", pkgname);
-	let tag = format!("{}:{}", image, pkgver);
+	let tags = docker_tags(config, pkg);
+	let tag = &tags[0];
 	info!("Building Docker image {}", tag);
-	let tar = build_tar(None, &dockerfile, &config.alpine.pubkey, None).await?;
+	let tar = context.to_tar()?;
 	build_image(
 		docker,
 		BuildImageOptions {
-			t: tag.clone(),
+			t: tag.as_str(),
 			pull: true,
 			nocache: true,
+			platform: config.alpine.docker_platform(),
 			..Default::default()
 		},
 		tar
 	)
 	.await?;
-	docker
-		.tag_image(
-			&tag,
-			Some(TagImageOptions {
-				repo: image.as_str(),
-				tag: "latest"
-			})
-		)
-		.await?;
-	if upload_docker {
-		docker_push(docker, &tag).await?;
-		docker_push(docker, &image).await?;
-	}
+	tag_image(docker, tag, &tags[1..]).await?;
 
 	Ok(())
 }
@@ -1,108 +1,96 @@
-use super::docker_run_abuild;
+use super::{docker_run_abuild, local_image, BuildContext, PrivKey};
 use crate::{
-	docker::{build_image, docker_push, run_container_to_completion, tar_header, IPv6CIDR},
+	docker::{build_image, run_container_to_completion, tag_image, IPv6CIDR},
+	report::{Status, TestResult},
 	Config
 };
 use anyhow::anyhow;
 use askama::Template;
 use bollard::{container, image::BuildImageOptions, Docker};
-use std::{io::Cursor, path::Path, process::exit, sync::Arc};
-use tokio::{
-	fs::{self, File},
-	io::{self, AsyncReadExt},
-	task::{spawn, JoinHandle}
+use std::{
+	sync::Arc,
+	time::{Duration, Instant}
 };
+use tokio::task::{spawn, JoinHandle};
 
-const DOCKER_IMAGE: &str = "ghcr.io/msrd0/alpine-rust";
-
-pub async fn up_to_date(repodir: &Path, config: &Config, channel: &str) -> bool {
+pub fn apk_key(config: &Config, channel: &str) -> String {
 	let rust = &config.rust[channel];
 	let pkgname = format!("rust-{}", channel);
 	let pkgver = match rust.date.as_ref() {
 		Some(date) => format!("{}.{}", rust.pkgver, date.format("%Y%m%d")),
-		None => format!("{}", rust.pkgver)
+		None => rust.pkgver.clone()
 	};
-	let path = format!(
-		"{}/alpine-rust/x86_64/{}-{}-r{}.apk",
-		config.alpine.version, pkgname, pkgver, rust.pkgrel
-	);
-	info!("Checking if {} is up to date ...", path);
-	match fs::metadata(repodir.join(path)).await {
-		Ok(_) => true,                                              // file exists
-		Err(err) if err.kind() == io::ErrorKind::NotFound => false, // not found
-		Err(err) => {
-			error!("Unable to check if package was up to date: {}", err);
-			// exiting is fine because no upcloud server was provisioned yet
-			exit(1);
-		}
-	}
+	super::apk_key(config, &pkgname, &pkgver, rust.pkgrel)
 }
 
-async fn build_tar(
+/// Return the docker tags of the default and the minimal image of this channel.
+pub fn docker_tags(config: &Config, channel: &str) -> (Vec<String>, Vec<String>) {
+	let (tag, minimal_tag) = match channel {
+		"stable" => ("latest".to_owned(), "minimal".to_owned()),
+		channel => (channel.to_owned(), format!("{}-minimal", channel))
+	};
+	let image = config.deploy.registry.image("alpine-rust");
+	(
+		super::docker_tags(config, &image, &tag),
+		super::docker_tags(config, &image, &minimal_tag)
+	)
+}
+
+async fn build_context(
 	apkbuild: Option<&str>,
 	dockerfile: &str,
 	include_compiler_test: bool,
-	pubkey: &str,
-	privkey: Option<&str>
-) -> anyhow::Result<Vec<u8>> {
-	let mut tar_buf: Vec<u8> = Vec::new();
-	let mut tar = tar::Builder::new(&mut tar_buf);
+	config: &Config,
+	privkey: PrivKey
+) -> anyhow::Result<BuildContext> {
+	let mut context = BuildContext::default();
 
 	// write the APKBUILD file
 	if let Some(apkbuild) = apkbuild {
-		let bytes = apkbuild.as_bytes();
-		let header = tar_header("APKBUILD", bytes.len());
-		tar.append(&header, Cursor::new(bytes))?;
+		context.add("APKBUILD", apkbuild);
 	}
 
 	// write the Dockerfile file
-	let bytes = dockerfile.as_bytes();
-	let header = tar_header("Dockerfile", bytes.len());
-	tar.append(&header, Cursor::new(bytes))?;
+	context.add("Dockerfile", dockerfile);
 
 	// include the compiler test if desired
 	if include_compiler_test {
 		const BYTES: &[u8] = include_bytes!(env!("SIMPLE_COMPILER_TEST"));
-		let header = tar_header("simple_compiler_test.tar", BYTES.len());
-		tar.append(&header, Cursor::new(BYTES))?;
+		context.add("simple_compiler_test.tar", BYTES);
 	}
 
-	// copy the public key
-	let mut file = File::open(pubkey).await?;
-	let mut bytes = Vec::<u8>::new();
-	file.read_to_end(&mut bytes).await?;
-	let header = tar_header(pubkey, bytes.len());
-	tar.append(&header, Cursor::new(bytes))?;
-
-	if let Some(privkey) = privkey {
-		// copy the private key
-		let mut file = File::open(privkey).await?;
-		let mut bytes = Vec::<u8>::new();
-		file.read_to_end(&mut bytes).await?;
-		let header = tar_header(privkey, bytes.len());
-		tar.append(&header, Cursor::new(bytes))?;
+	// copy the keys
+	context.add_file(&config.alpine.pubkey).await?;
+	context.add_privkey(&config.alpine.privkey, privkey).await?;
+
+	Ok(context)
+}
+
+/// Return the build context of the image that builds the packages of this channel.
+pub async fn abuild_context(config: &Config, channel: &str, jobs: u16, privkey: PrivKey) -> anyhow::Result<BuildContext> {
+	let apkbuild: String = config.rust_apkbuild(channel).render()?;
+	let dockerfile = config.rust_dockerfile_abuild(channel, jobs).render()?;
+	let mut context = build_context(Some(&apkbuild), &dockerfile, false, config, privkey).await?;
+	for patch in config.patches.local(&config.rust[channel].rustver()).unwrap_or_default() {
+		context.add(&patch.name, patch.content.as_slice());
 	}
+	Ok(context)
+}
 
-	// finish the tar archive
-	tar.finish()?;
-	drop(tar);
-	Ok(tar_buf)
+/// Return the build contexts of the minimal and the default docker image of this channel.
+pub async fn image_contexts(config: &Config, channel: &str) -> anyhow::Result<(BuildContext, BuildContext)> {
+	let dockerfile = config.rust_dockerfile_minimal(channel).render()?;
+	let minimal = build_context(None, &dockerfile, false, config, PrivKey::Omit).await?;
+	let dockerfile = config.rust_dockerfile_default(channel).render()?;
+	let default = build_context(None, &dockerfile, false, config, PrivKey::Omit).await?;
+	Ok((minimal, default))
 }
 
 async fn docker_build_abuild(docker: &Docker, tag: &str, config: &Config, channel: &str, jobs: u16) -> anyhow::Result<()> {
 	info!("Building Docker image {}", tag);
 
 	// create the context tar for docker build
-	let apkbuild: String = config.rust_apkbuild(channel).render()?;
-	let dockerfile = config.rust_dockerfile_abuild(channel, jobs).render()?;
-	let tar = build_tar(
-		Some(&apkbuild),
-		&dockerfile,
-		false,
-		&config.alpine.pubkey,
-		Some(&config.alpine.privkey)
-	)
-	.await?;
+	let tar = abuild_context(config, channel, jobs, PrivKey::Include).await?.to_tar()?;
 
 	// build the docker image
 	build_image(
@@ -110,6 +98,7 @@ async fn docker_build_abuild(docker: &Docker, tag: &str, config: &Config, channe
 		BuildImageOptions {
 			t: tag.to_owned(),
 			pull: true,
+			platform: config.alpine.docker_platform().to_owned(),
 			..Default::default()
 		},
 		tar
@@ -139,17 +128,20 @@ async fn docker_run_test(docker: Arc<Docker>, img: String, cmd: String) -> anyho
 	run_container_to_completion(&docker, &container.id).await
 }
 
-async fn docker_build_dockerfile(
-	docker: &Docker,
-	tag: &str,
-	include_compiler_test: bool,
-	dockerfile: &str,
-	config: &Config
-) -> anyhow::Result<()> {
+type TimedResult = (Duration, anyhow::Result<()>);
+
+/// Run a test command and measure how long it took.
+async fn timed_docker_run_test(docker: Arc<Docker>, img: String, cmd: String) -> TimedResult {
+	let start = Instant::now();
+	let res = docker_run_test(docker, img, cmd).await;
+	(start.elapsed(), res)
+}
+
+async fn docker_build_context(docker: &Docker, config: &Config, tag: &str, context: BuildContext) -> anyhow::Result<()> {
 	info!("Building Docker image {}", tag);
 
 	// create the context tar for docker build
-	let tar = build_tar(None, dockerfile, include_compiler_test, &config.alpine.pubkey, None).await?;
+	let tar = context.to_tar()?;
 
 	// build the docker image
 	build_image(
@@ -158,6 +150,7 @@ async fn docker_build_dockerfile(
 			t: tag,
 			pull: true,
 			nocache: true,
+			platform: config.alpine.docker_platform(),
 			..Default::default()
 		},
 		tar
@@ -176,25 +169,29 @@ pub async fn build_package(
 ) -> anyhow::Result<()> {
 	info!("Building Rust {}", channel);
 
-	let img = format!("alpine-rust-builder-{}", channel);
+	let img = local_image(config, "builder", channel);
 	docker_build_abuild(docker, &img, config, channel, jobs).await?;
 	docker_run_abuild(docker, &img, repomount).await?;
 
 	Ok(())
 }
 
+/// Test the packages of this channel and return the results of the individual tests. An error is
+/// only returned if the tests could not be run at all, use [check_test_results] to check whether
+/// any of the tests failed.
 pub async fn test_package(
 	docker: Arc<Docker>,
 	cidr_v6: &IPv6CIDR<String>,
 	config: &Config,
 	channel: &str
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Vec<TestResult>> {
 	info!("Testing build packages ...");
 
-	let tag = format!("alpine-rust-test-{}", channel);
+	let tag = local_image(config, "test", channel);
 
 	let dockerfile = config.rust_dockerfile_test(cidr_v6).render()?;
-	docker_build_dockerfile(&docker, &tag, true, &dockerfile, config).await?;
+	let context = build_context(None, &dockerfile, true, config, PrivKey::Omit).await?;
+	docker_build_context(&docker, config, &tag, context).await?;
 
 	// TODO is this the best way to get all packages?
 	let packages = [
@@ -217,21 +214,24 @@ pub async fn test_package(
 	.map(|tpl| tpl.replace("{}", &channel))
 	.collect::<Vec<_>>();
 
-	let mut tests: Vec<(JoinHandle<anyhow::Result<()>>, String)> = Vec::new();
+	// every test has a task, a name and an error message
+	let mut tests: Vec<(JoinHandle<TimedResult>, String, String)> = Vec::new();
 
 	// first of all, let's test that every package can be installed on its own
 	for pkg in &packages {
 		let cmd = format!("apk add {}", pkg);
-		let task = spawn(docker_run_test(docker.clone(), tag.clone(), cmd));
+		let task = spawn(timed_docker_run_test(docker.clone(), tag.clone(), cmd));
+		let name = format!("install {}", pkg);
 		let err = format!("Failed to install {}", pkg);
-		tests.push((task, err));
+		tests.push((task, name, err));
 	}
 
 	// next, let's test they can all be installed alongside each other
 	let cmd = format!("apk add {}", packages.join(" "));
-	let task = spawn(docker_run_test(docker.clone(), tag.clone(), cmd));
+	let task = spawn(timed_docker_run_test(docker.clone(), tag.clone(), cmd));
+	let name = "install all packages".to_owned();
 	let err = format!("Failed to install all packages for {}", channel);
-	tests.push((task, err));
+	tests.push((task, name, err));
 
 	// and finally, test a small rust program that uses derive macros
 	let cmd = [
@@ -242,51 +242,41 @@ pub async fn test_package(
 		"cargo test --offline --lib".to_owned()
 	]
 	.join(" && ");
-	let task = spawn(docker_run_test(docker.clone(), tag.clone(), cmd));
+	let task = spawn(timed_docker_run_test(docker.clone(), tag.clone(), cmd));
+	let name = "simple compiler test".to_owned();
 	let err = format!("Failed to run simple rust program with {}", channel);
-	tests.push((task, err));
-
-	let mut tests_total: u32 = 0;
-	let mut tests_failed: u32 = 0;
-	for (test, err_msg) in tests {
-		tests_total += 1;
-		if let Err(err) = test.await? {
-			tests_failed += 1;
+	tests.push((task, name, err));
+
+	let mut results = Vec::with_capacity(tests.len());
+	for (test, name, err_msg) in tests {
+		let (duration, res) = test.await?;
+		if let Err(err) = &res {
 			error!("{}: {}", err_msg, err);
 		}
+		results.push(TestResult::new(name, duration, res));
 	}
+	Ok(results)
+}
 
-	if tests_failed == 0 {
+/// Return an error if any of the tests failed.
+pub fn check_test_results(results: &[TestResult]) -> anyhow::Result<()> {
+	let failed = results.iter().filter(|test| test.status == Status::Failure).count();
+	if failed == 0 {
 		Ok(())
 	} else {
-		Err(anyhow!("{} out of {} tests failed", tests_failed, tests_total))
+		Err(anyhow!("{} out of {} tests failed", failed, results.len()))
 	}
 }
 
-pub async fn build_and_upload_docker(
-	docker: &Docker,
-	config: &Config,
-	channel: &str,
-	upload_docker: bool
-) -> anyhow::Result<()> {
-	let (tag, minimal_tag) = match channel {
-		"stable" => ("latest", "minimal".to_owned()),
-		channel => (channel, format!("{}-minimal", channel))
-	};
+/// Build the minimal and the default docker image of this channel. Use [docker_tags] to push them.
+pub async fn build_docker(docker: &Docker, config: &Config, channel: &str) -> anyhow::Result<()> {
+	let (tags, minimal_tags) = docker_tags(config, channel);
+	let (minimal, default) = image_contexts(config, channel).await?;
 
-	let img = format!("{}:{}", DOCKER_IMAGE, minimal_tag);
-	let dockerfile = config.rust_dockerfile_minimal(channel).render()?;
-	docker_build_dockerfile(docker, &img, false, &dockerfile, config).await?;
-	if upload_docker {
-		docker_push(docker, &img).await?;
-	}
-
-	let img = format!("{}:{}", DOCKER_IMAGE, tag);
-	let dockerfile = config.rust_dockerfile_default(channel).render()?;
-	docker_build_dockerfile(docker, &img, false, &dockerfile, config).await?;
-	if upload_docker {
-		docker_push(docker, &img).await?;
-	}
+	docker_build_context(docker, config, &minimal_tags[0], minimal).await?;
+	tag_image(docker, &minimal_tags[0], &minimal_tags[1..]).await?;
+	docker_build_context(docker, config, &tags[0], default).await?;
+	tag_image(docker, &tags[0], &tags[1..]).await?;
 
 	Ok(())
 }
@@ -1,14 +1,20 @@
-use crate::GITHUB_TOKEN;
-use anyhow::bail;
+use crate::config::DeployRegistry;
+use anyhow::{bail, Context};
 use bollard::{
 	auth::DockerCredentials,
 	container::{LogsOptions, RemoveContainerOptions},
-	image::BuildImageOptions,
+	image::{BuildImageOptions, TagImageOptions},
 	Docker
 };
 use futures_util::StreamExt;
 use serde::Serialize;
-use std::{hash::Hash, time::Duration};
+use std::{
+	collections::HashSet,
+	fmt::{self, Display},
+	hash::Hash,
+	sync::Mutex,
+	time::Duration
+};
 use tokio::time::delay_for;
 
 mod caddy;
@@ -35,7 +41,7 @@ where
 {
 	let mut img_stream = docker.build_image(options, None, Some(tar.into()));
 	while let Some(status) = img_stream.next().await {
-		let status = status.expect("Failed to build image");
+		let status = status.context("Failed to build image")?;
 		if let Some(log) = status.stream {
 			print!("{}", log);
 		}
@@ -48,7 +54,82 @@ where
 	Ok(())
 }
 
+/// Add more tags to an image that was built with the tag `image`.
+pub async fn tag_image(docker: &Docker, image: &str, tags: &[String]) -> anyhow::Result<()> {
+	for tag in tags {
+		let (repo, tag) = match tag.rfind(':') {
+			Some(idx) => (&tag[..idx], &tag[idx + 1..]),
+			None => (tag.as_str(), "latest")
+		};
+		docker
+			.tag_image(image, Some(TagImageOptions { repo, tag }))
+			.await
+			.with_context(|| format!("Unable to tag {} as {}:{}", image, repo, tag))?;
+	}
+	Ok(())
+}
+
+/// The error returned when a container finished with a non-zero exit code.
+#[derive(Debug)]
+pub struct ContainerExit {
+	pub container_id: String,
+	pub exit_code: i64
+}
+
+impl Display for ContainerExit {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Container {} finished with exit code {}", self.container_id, self.exit_code)
+	}
+}
+
+impl std::error::Error for ContainerExit {}
+
+impl ContainerExit {
+	/// Return the exit code of the container that caused the error, if any.
+	pub fn exit_code(err: &anyhow::Error) -> Option<i64> {
+		err.chain()
+			.find_map(|err| err.downcast_ref::<Self>())
+			.map(|exit| exit.exit_code)
+	}
+}
+
+lazy_static! {
+	static ref RUNNING_CONTAINERS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Start the container and wait for it to finish. Returns a [ContainerExit] error if the container
+/// finished with a non-zero exit code.
 pub async fn run_container_to_completion(docker: &Docker, container_id: &str) -> anyhow::Result<()> {
+	// if this future is dropped before the container finished, it is removed by
+	// remove_running_containers
+	RUNNING_CONTAINERS.lock().unwrap().insert(container_id.to_owned());
+	let res = run_container(docker, container_id).await;
+	RUNNING_CONTAINERS.lock().unwrap().remove(container_id);
+	res
+}
+
+/// Forcefully remove all containers whose [run_container_to_completion] was interrupted.
+pub async fn remove_running_containers(docker: &Docker) {
+	let containers = RUNNING_CONTAINERS.lock().unwrap().drain().collect::<Vec<_>>();
+	for container_id in containers {
+		info!("Removing container {}", container_id);
+		let res = docker
+			.remove_container(
+				&container_id,
+				Some(RemoveContainerOptions {
+					v: true,
+					force: true,
+					..Default::default()
+				})
+			)
+			.await;
+		if let Err(err) = res {
+			error!("Unable to remove container {}: {}", container_id, err);
+		}
+	}
+}
+
+async fn run_container(docker: &Docker, container_id: &str) -> anyhow::Result<()> {
 	// start the container
 	docker.start_container::<String>(container_id, None).await?;
 	info!("Started container {}", container_id);
@@ -99,10 +180,11 @@ pub async fn run_container_to_completion(docker: &Docker, container_id: &str) ->
 	let exit_code = get_exit_code(docker, container_id).await?;
 	info!("Container {} has stopped with exit code {}", container_id, exit_code);
 	if exit_code != 0 {
-		return Err(anyhow::Error::msg(format!(
-			"Container {} finished with exit code {}",
-			container_id, exit_code
-		)));
+		return Err(ContainerExit {
+			container_id: container_id.to_owned(),
+			exit_code
+		}
+		.into());
 	}
 	Ok(())
 }
@@ -121,14 +203,14 @@ pub async fn remove_container(docker: &Docker, container_id: &str) -> anyhow::Re
 	Ok(())
 }
 
-pub async fn docker_push(docker: &Docker, tag: &str) -> anyhow::Result<()> {
+pub async fn docker_push(docker: &Docker, registry: &DeployRegistry, tag: &str) -> anyhow::Result<()> {
 	info!("Pushing Docker image {}", tag);
 	let mut push_stream = docker.push_image::<String>(
 		&tag,
 		None,
 		Some(DockerCredentials {
-			username: Some("drone-msrd0-eu".to_owned()),
-			password: Some(GITHUB_TOKEN.clone()),
+			username: Some(registry.username.clone()),
+			password: Some(registry.password.get()?),
 			..Default::default()
 		})
 	);
@@ -0,0 +1,73 @@
+use std::fmt::{self, Display};
+
+/// The exit codes of the process, shown in the help output.
+pub const EXIT_CODES: &str = "EXIT CODES:
+    0      Success
+    1      Uncategorized error
+    2      Configuration error: invalid config, arguments or missing credentials
+    3      Upstream error: a channel manifest, source tarball or the repository is unavailable
+    4      Server error: the build server could not be provisioned
+    5      Build error: a package or docker image failed to build
+    6      Test error: a package failed its tests
+    7      Publish error: packages or docker images could not be published
+    128+N  Interrupted by signal N";
+
+/// The category of an error. It is attached to an error as context using [ErrorKindExt::kind]
+/// and determines the exit code of the process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+	Config,
+	Upstream,
+	Server,
+	Build,
+	Test,
+	Publish
+}
+
+impl ErrorKind {
+	/// Return the category of the error, if it has one.
+	pub fn of(err: &anyhow::Error) -> Option<Self> {
+		err.downcast_ref::<Self>().copied()
+	}
+
+	pub fn exit_code(self) -> i32 {
+		match self {
+			Self::Config => 2,
+			Self::Upstream => 3,
+			Self::Server => 4,
+			Self::Build => 5,
+			Self::Test => 6,
+			Self::Publish => 7
+		}
+	}
+}
+
+impl Display for ErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Config => "configuration error",
+			Self::Upstream => "upstream error",
+			Self::Server => "server error",
+			Self::Build => "build error",
+			Self::Test => "test error",
+			Self::Publish => "publish error"
+		})
+	}
+}
+
+pub trait ErrorKindExt<T> {
+	/// Categorize the error, unless it was already categorized.
+	fn kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ErrorKindExt<T> for Result<T, E> {
+	fn kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+		self.map_err(|err| {
+			let err = err.into();
+			match ErrorKind::of(&err) {
+				Some(_) => err,
+				None => err.context(kind)
+			}
+		})
+	}
+}
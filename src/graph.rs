@@ -0,0 +1,497 @@
+use crate::{
+	build::packages::Package,
+	config::Config,
+	error::{ErrorKind, ErrorKindExt},
+	report::Status,
+	Packagelike
+};
+use anyhow::{anyhow, bail};
+use futures_util::{stream::FuturesUnordered, FutureExt, StreamExt};
+use itertools::Itertools;
+use std::{collections::BTreeSet, future::Future};
+
+/// The dependency graph of all packages defined in the config. Every package knows which other
+/// packages need to be present in the repository before it can be built.
+pub struct BuildGraph<'a> {
+	nodes: Vec<Packagelike<'a>>,
+	dependencies: Vec<BTreeSet<usize>>,
+	order: Vec<usize>
+}
+
+impl<'a> BuildGraph<'a> {
+	pub fn new(config: &'a Config) -> anyhow::Result<Self> {
+		let mut nodes: Vec<Packagelike<'a>> = Vec::new();
+		nodes.extend(config.packages.llvm.iter().map(Packagelike::LLVM));
+		nodes.extend(
+			config
+				.rust
+				.keys()
+				.sorted()
+				.map(|channel| Packagelike::Rust { channel: channel.as_str() })
+		);
+		nodes.extend(config.packages.crates.iter().map(Packagelike::Crate));
+		nodes.extend(config.packages.custom.iter().map(Packagelike::Custom));
+
+		let find_rust = |channel: &str| {
+			nodes
+				.iter()
+				.position(|node| matches!(node, Packagelike::Rust { channel: ch } if *ch == channel))
+		};
+		let find_llvm = |llvmver: u32| {
+			let pkgname = format!("llvm{}", llvmver);
+			nodes
+				.iter()
+				.position(|node| matches!(node, Packagelike::LLVM(llvm) if llvm.pkgname() == pkgname))
+		};
+
+		let mut dependencies = Vec::with_capacity(nodes.len());
+		for node in &nodes {
+			let mut deps = BTreeSet::new();
+			match node {
+				Packagelike::LLVM(_) => {},
+
+				Packagelike::Rust { channel } => {
+					let rust = &config.rust[*channel];
+					if !rust.bootsys {
+						match find_rust(&rust.bootver) {
+							Some(idx) => deps.insert(idx),
+							None => bail!(
+								"Rust {} bootstraps from Rust {} which does not exist in the config",
+								channel,
+								rust.bootver
+							)
+						};
+					}
+					match find_llvm(rust.llvmver) {
+						Some(idx) => {
+							deps.insert(idx);
+						},
+						None if config.alpine.llvm.contains(&rust.llvmver) => {},
+						None => bail!(
+							"Rust {} requires LLVM {} which neither exists in the config nor is provided by Alpine {}",
+							channel,
+							rust.llvmver,
+							config.alpine.version
+						)
+					}
+				},
+
+				// the crate APKBUILD has makedepends="cargo-stable"
+				Packagelike::Crate(krate) => match find_rust("stable") {
+					Some(idx) => {
+						deps.insert(idx);
+					},
+					None => bail!(
+						"Crate {} requires Rust stable which does not exist in the config",
+						krate.crate_name
+					)
+				},
+
+				Packagelike::Custom(custom) => {
+					for dep in &custom.depends {
+						match nodes.iter().position(|node| node.name() == dep.as_str()) {
+							Some(idx) => deps.insert(idx),
+							None => bail!(
+								"Package {} depends on {} which does not exist in the config",
+								custom.pkgname,
+								dep
+							)
+						};
+					}
+				}
+			}
+			dependencies.push(deps);
+		}
+
+		let order = toposort(&nodes, &dependencies)?;
+		Ok(Self {
+			nodes,
+			dependencies,
+			order
+		})
+	}
+
+	/// Return all packages in the graph in an order that builds every package after its
+	/// dependencies.
+	pub fn packages(&self) -> impl Iterator<Item = Packagelike<'a>> + '_ {
+		self.order.iter().map(move |idx| self.nodes[*idx])
+	}
+
+	/// Return the direct dependencies of a package.
+	pub fn dependencies(&self, pkg: &Packagelike<'_>) -> impl Iterator<Item = Packagelike<'a>> + '_ {
+		let idx = self.nodes.iter().position(|node| node == pkg);
+		idx.into_iter()
+			.flat_map(move |idx| self.dependencies[idx].iter())
+			.map(move |dep| self.nodes[*dep])
+	}
+
+	/// Run `f` for every package in `pkgs`. A package is only started once none of its
+	/// dependencies are still waiting or running, and at most `parallel` packages are processed
+	/// at the same time. After the first error, no new packages are started unless `keep_going`
+	/// is set, in which case only the packages that depend on a failed package are skipped. All
+	/// running packages are awaited before an error listing every failed package is returned. That
+	/// error has the category of the first error that was categorized. Every package that is
+	/// skipped or never started is passed to `skip` together with the reason.
+	pub async fn execute<F, Fut, S>(
+		&self,
+		pkgs: &[Packagelike<'a>],
+		parallel: usize,
+		keep_going: bool,
+		mut f: F,
+		mut skip: S
+	) -> anyhow::Result<()>
+	where
+		F: FnMut(Packagelike<'a>) -> Fut,
+		Fut: Future<Output = anyhow::Result<()>>,
+		S: FnMut(Packagelike<'a>, Status, &str)
+	{
+		let mut pending: Vec<Packagelike<'a>> = pkgs.to_vec();
+		let mut running: Vec<Packagelike<'a>> = Vec::new();
+		let mut failed: Vec<Packagelike<'a>> = Vec::new();
+		let mut skipped: Vec<Packagelike<'a>> = Vec::new();
+		let mut kind: Option<ErrorKind> = None;
+		let mut futures = FuturesUnordered::new();
+
+		loop {
+			// skip all packages that depend on a failed or skipped package
+			while let Some((idx, dep)) = pending.iter().enumerate().find_map(|(idx, pkg)| {
+				self.dependencies(pkg)
+					.find(|dep| failed.contains(dep) || skipped.contains(dep))
+					.map(|dep| (idx, dep))
+			}) {
+				let pkg = pending.remove(idx);
+				let reason = if failed.contains(&dep) {
+					format!("dependency {} failed", dep.name())
+				} else {
+					format!("dependency {} was skipped", dep.name())
+				};
+				warn!("Skipping update of {} because its {}", pkg.name(), reason);
+				skip(pkg, Status::Skipped, &reason);
+				skipped.push(pkg);
+			}
+
+			while (keep_going || failed.is_empty()) && running.len() < parallel.max(1) {
+				let ready = pending.iter().position(|pkg| {
+					self.dependencies(pkg)
+						.all(|dep| !pending.contains(&dep) && !running.contains(&dep))
+				});
+				let pkg = match ready {
+					Some(idx) => pending.remove(idx),
+					None => break
+				};
+				info!("Starting update of {}", pkg.name());
+				running.push(pkg);
+				futures.push(f(pkg).map(move |res| (pkg, res)));
+			}
+
+			let (pkg, pkg_res) = match futures.next().await {
+				Some(next) => next,
+				None => break
+			};
+			running.retain(|running| running != &pkg);
+			match pkg_res {
+				Ok(()) => info!("Finished update of {}", pkg.name()),
+				Err(err) => {
+					error!("Update of {} failed: {:#}", pkg.name(), err);
+					kind = kind.or_else(|| ErrorKind::of(&err));
+					failed.push(pkg);
+				}
+			}
+		}
+
+		for pkg in &pending {
+			skip(*pkg, Status::NotRun, "not started because of previous errors");
+		}
+
+		if failed.is_empty() {
+			return Ok(());
+		}
+		let mut msg = format!(
+			"Failed to update {} out of {} packages: {}",
+			failed.len(),
+			pkgs.len(),
+			failed.iter().map(|pkg| pkg.name()).join(", ")
+		);
+		if !skipped.is_empty() {
+			msg += &format!(
+				" (skipped because of failed dependencies: {})",
+				skipped.iter().map(|pkg| pkg.name()).join(", ")
+			);
+		}
+		if !pending.is_empty() {
+			msg += &format!(
+				" (not started: {})",
+				pending.iter().map(|pkg| pkg.name()).join(", ")
+			);
+		}
+		let err = anyhow!(msg);
+		match kind {
+			Some(kind) => Err(err).kind(kind),
+			None => Err(err)
+		}
+	}
+}
+
+/// Sort the nodes topologically using Kahn's algorithm. Nodes that do not depend on each other
+/// keep the order in which they were inserted into the graph.
+fn toposort(nodes: &[Packagelike<'_>], dependencies: &[BTreeSet<usize>]) -> anyhow::Result<Vec<usize>> {
+	let mut missing_deps: Vec<usize> = dependencies.iter().map(|deps| deps.len()).collect();
+	let mut ready: BTreeSet<usize> = (0..nodes.len()).filter(|idx| missing_deps[*idx] == 0).collect();
+	let mut order = Vec::with_capacity(nodes.len());
+
+	while let Some(idx) = ready.iter().next().copied() {
+		ready.remove(&idx);
+		order.push(idx);
+		for (dependent, deps) in dependencies.iter().enumerate() {
+			if deps.contains(&idx) {
+				missing_deps[dependent] -= 1;
+				if missing_deps[dependent] == 0 {
+					ready.insert(dependent);
+				}
+			}
+		}
+	}
+
+	if order.len() != nodes.len() {
+		// the unsorted nodes include the ones that only depend on a cycle, so repeatedly drop the
+		// nodes that no other unsorted node depends on
+		let mut cycle: BTreeSet<usize> = (0..nodes.len()).filter(|idx| missing_deps[*idx] > 0).collect();
+		while let Some(idx) = cycle
+			.iter()
+			.copied()
+			.find(|idx| !cycle.iter().any(|dependent| dependencies[*dependent].contains(idx)))
+		{
+			cycle.remove(&idx);
+		}
+		let cycle = cycle.into_iter().map(|idx| nodes[idx].name()).join(", ");
+		bail!("Dependency cycle detected between the following packages: {}", cycle);
+	}
+	Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::anyhow;
+	use std::cell::RefCell;
+
+	const ALPINE: &str = "[alpine]\nversions = [\"3.13\"]\npubkey = \"key.rsa.pub\"\nprivkey = \"key.rsa\"\nllvm = [10]\n";
+
+	fn rust(channel: &str, bootver: &str, bootsys: bool, llvmver: u32) -> String {
+		format!(
+			"[rust.{:?}]\npkgver = \"1.0.0\"\npkgrel = 0\nbootver = {:?}\nbootsys = {}\nllvmver = {}\nsha512sums = \"\"\n",
+			channel, bootver, bootsys, llvmver
+		)
+	}
+
+	fn llvm(pkgver: &str) -> String {
+		format!("[[packages.llvm]]\npkgver = {:?}\npkgrel = 0\nsha512sum = \"\"\n", pkgver)
+	}
+
+	fn krate(crate_name: &str) -> String {
+		format!(
+			"[[packages.crate]]\ncrate_name = {:?}\nversion = \"1.0.0\"\npkgrel = 0\ndescription = \"\"\nlicense = \"MIT\"\n\
+			 dependencies = []\nsha512sum = \"\"\n",
+			crate_name
+		)
+	}
+
+	fn custom(pkgname: &str, depends: &[&str]) -> String {
+		format!(
+			"[[packages.custom]]\npkgname = {:?}\npkgver = \"1.0\"\npkgrel = 0\napkbuild = \"APKBUILD\"\ndepends = {:?}\n",
+			pkgname, depends
+		)
+	}
+
+	fn config(packages: &[String]) -> Config {
+		let config: Config = toml::from_str(&format!("{}{}", ALPINE, packages.concat())).unwrap();
+		config.for_alpine("3.13", "x86_64")
+	}
+
+	fn names(pkgs: &[Packagelike<'_>]) -> Vec<String> {
+		pkgs.iter().map(|pkg| pkg.name().into_owned()).collect()
+	}
+
+	fn error(config: &Config) -> String {
+		BuildGraph::new(config).err().unwrap().to_string()
+	}
+
+	#[test]
+	fn bootstrap_order() {
+		// 1.100 sorts before 1.40 and 1.99, but needs to be built after 1.99
+		let config = config(&[
+			rust("1.99", "1.98", true, 10),
+			rust("1.100", "1.99", false, 11),
+			rust("1.40", "1.39", true, 10),
+			rust("stable", "1.100", false, 11),
+			llvm("11.0.1"),
+			krate("foo")
+		]);
+		let graph = BuildGraph::new(&config).unwrap();
+		let pkgs = graph.packages().collect::<Vec<_>>();
+		assert_eq!(names(&pkgs), vec!["llvm11", "1.40", "1.99", "1.100", "stable", "foo"]);
+	}
+
+	#[test]
+	fn bootsys_adds_no_edge() {
+		let config = config(&[rust("1.41", "1.40", true, 10)]);
+		let graph = BuildGraph::new(&config).unwrap();
+		let pkgs = graph.packages().collect::<Vec<_>>();
+		assert_eq!(names(&pkgs), vec!["1.41"]);
+	}
+
+	#[test]
+	fn missing_bootver() {
+		let config = config(&[rust("1.41", "1.40", false, 10)]);
+		assert_eq!(
+			error(&config),
+			"Rust 1.41 bootstraps from Rust 1.40 which does not exist in the config"
+		);
+	}
+
+	#[test]
+	fn llvm_from_package_or_alpine() {
+		let config = config(&[rust("1.41", "1.40", true, 11), rust("1.42", "1.41", false, 10), llvm("11.0.1")]);
+		assert!(BuildGraph::new(&config).is_ok());
+	}
+
+	#[test]
+	fn missing_llvmver() {
+		let config = config(&[rust("1.41", "1.40", true, 12), llvm("11.0.1")]);
+		assert_eq!(
+			error(&config),
+			"Rust 1.41 requires LLVM 12 which neither exists in the config nor is provided by Alpine 3.13"
+		);
+	}
+
+	#[test]
+	fn missing_stable() {
+		let config = config(&[rust("1.41", "1.40", true, 10), krate("foo")]);
+		assert_eq!(error(&config), "Crate foo requires Rust stable which does not exist in the config");
+	}
+
+	#[test]
+	fn rust_dependencies() {
+		let config = config(&[
+			rust("1.41", "1.40", true, 11),
+			rust("1.42", "1.41", false, 10),
+			rust("stable", "1.42", false, 10),
+			llvm("11.0.1"),
+			krate("foo")
+		]);
+		let graph = BuildGraph::new(&config).unwrap();
+		let pkgs = graph.packages().collect::<Vec<_>>();
+		let deps = |name: &str| {
+			let pkg = pkgs.iter().find(|pkg| pkg.name() == name).unwrap();
+			names(&graph.dependencies(pkg).collect::<Vec<_>>())
+		};
+		assert_eq!(deps("llvm11"), Vec::<String>::new());
+		assert_eq!(deps("1.41"), vec!["llvm11"]);
+		assert_eq!(deps("1.42"), vec!["1.41"]);
+		assert_eq!(deps("foo"), vec!["stable"]);
+	}
+
+	#[test]
+	fn custom_dependencies() {
+		let config = config(&[
+			rust("stable", "1.41", true, 10),
+			custom("d", &["b", "c"]),
+			custom("c", &["a"]),
+			custom("b", &["a", "stable"]),
+			custom("a", &[])
+		]);
+		let graph = BuildGraph::new(&config).unwrap();
+		let pkgs = graph.packages().collect::<Vec<_>>();
+		assert_eq!(names(&pkgs), vec!["stable", "a", "c", "b", "d"]);
+	}
+
+	#[test]
+	fn missing_custom_dependency() {
+		let config = config(&[custom("a", &["b"])]);
+		assert_eq!(error(&config), "Package a depends on b which does not exist in the config");
+	}
+
+	#[test]
+	fn detect_cycle() {
+		// 1.43 only depends on the cycle
+		let config = config(&[
+			rust("1.41", "1.42", false, 10),
+			rust("1.42", "1.41", false, 10),
+			rust("1.43", "1.42", false, 10)
+		]);
+		assert_eq!(
+			error(&config),
+			"Dependency cycle detected between the following packages: 1.41, 1.42"
+		);
+	}
+
+	fn execute_config() -> Config {
+		config(&[
+			rust("1.41", "1.40", true, 11),
+			rust("1.42", "1.41", false, 10),
+			rust("1.50", "1.49", true, 10),
+			llvm("11.0.1")
+		])
+	}
+
+	/// Execute the graph with one package at a time, failing 1.41. Returns the started packages,
+	/// the skipped packages and the error.
+	async fn execute_failing(
+		graph: &BuildGraph<'_>,
+		keep_going: bool
+	) -> (Vec<String>, Vec<(String, Status, String)>, String) {
+		let pkgs = graph.packages().collect::<Vec<_>>();
+		let started = RefCell::new(Vec::new());
+		let mut skipped = Vec::new();
+		let res = graph
+			.execute(
+				&pkgs,
+				1,
+				keep_going,
+				|pkg| {
+					started.borrow_mut().push(pkg.name().into_owned());
+					let res = match &*pkg.name() {
+						"1.41" => Err(anyhow!("failed")),
+						_ => Ok(())
+					};
+					async move { res }
+				},
+				|pkg, status, reason| skipped.push((pkg.name().into_owned(), status, reason.to_owned()))
+			)
+			.await;
+		(started.into_inner(), skipped, res.unwrap_err().to_string())
+	}
+
+	#[tokio::test]
+	async fn stop_after_failure() {
+		let config = execute_config();
+		let graph = BuildGraph::new(&config).unwrap();
+		let (started, skipped, err) = execute_failing(&graph, false).await;
+		assert_eq!(started, vec!["llvm11", "1.41"]);
+		assert_eq!(skipped, vec![
+			("1.42".to_owned(), Status::Skipped, "dependency 1.41 failed".to_owned()),
+			("1.50".to_owned(), Status::NotRun, "not started because of previous errors".to_owned())
+		]);
+		assert_eq!(
+			err,
+			"Failed to update 1 out of 4 packages: 1.41 (skipped because of failed dependencies: 1.42) (not started: 1.50)"
+		);
+	}
+
+	#[tokio::test]
+	async fn skip_dependents_of_failed() {
+		let config = execute_config();
+		let graph = BuildGraph::new(&config).unwrap();
+		let (started, skipped, err) = execute_failing(&graph, true).await;
+		assert_eq!(started, vec!["llvm11", "1.41", "1.50"]);
+		assert_eq!(skipped, vec![(
+			"1.42".to_owned(),
+			Status::Skipped,
+			"dependency 1.41 failed".to_owned()
+		)]);
+		assert_eq!(
+			err,
+			"Failed to update 1 out of 4 packages: 1.41 (skipped because of failed dependencies: 1.42)"
+		);
+	}
+}
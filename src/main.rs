@@ -5,47 +5,56 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+use anyhow::{anyhow, Context};
+use askama::Template;
 use bollard::Docker;
-use either::Either;
-use futures_util::{stream, FutureExt, StreamExt};
 use itertools::Itertools;
 use log::LevelFilter;
 use std::{
 	borrow::Cow,
-	cmp::Ordering,
 	collections::BTreeSet,
-	env,
 	future::Future,
 	path::{Path, PathBuf},
-	process::exit,
-	sync::Arc
+	process::exit
 };
 use structopt::StructOpt;
-use tempfile::tempdir;
-use tokio::{
-	fs::{self, File},
-	io::AsyncReadExt
-};
+use tempfile::{tempdir, TempDir};
+use tokio::fs;
 
 mod build;
 mod config;
 mod docker;
+mod error;
+mod graph;
 mod metadata;
+mod plan;
+mod prune;
 mod repo;
+mod report;
 mod server;
+mod session;
+mod signal;
+mod state;
 mod templates;
+mod validate;
 
-use build::packages::Package;
+use build::{packages::Package, BuildContext, PrivKey};
 use config::*;
-use server::{local::LocalServer, upcloud::UpcloudServer, Server};
+use error::{ErrorKind, ErrorKindExt};
+use graph::BuildGraph;
+use plan::Reason;
+use repo::Storage;
+use report::{Report, Status};
+use session::{ServerKind, Session};
+use state::{Phase, State};
 
 lazy_static! {
 	static ref CLIENT: reqwest::Client = reqwest::Client::new();
-	static ref GITHUB_TOKEN: String = env::var("GITHUB_TOKEN").expect("GITHUB_TOKEN must be set");
 }
 
 /// Utility to compile Rust packages for Alpine Linux.
 #[derive(Debug, StructOpt)]
+#[structopt(after_help = error::EXIT_CODES)]
 struct Args {
 	/// Verbose mode (-v, -vv, -vvv)
 	#[structopt(short, long, parse(from_occurrences))]
@@ -55,10 +64,6 @@ struct Args {
 	#[structopt(long, default_value = "config.toml")]
 	config: PathBuf,
 
-	/// Update the configuration file if a newer rust version was found
-	#[structopt(short = "c", long)]
-	update_config: bool,
-
 	/// Use custom dir to download the repository
 	#[structopt(short, long)]
 	repodir: Option<PathBuf>,
@@ -67,55 +72,216 @@ struct Args {
 	#[structopt(long)]
 	cache: Option<PathBuf>,
 
-	/// Skip updating metadata
+	/// Write a JSON report of all packages and phases to this file. Defaults to a file next to the
+	/// repodir, or report.json if no repodir was specified.
 	#[structopt(long)]
-	skip_metadata: bool,
+	report: Option<PathBuf>,
 
-	/// Skip building rust packages
-	#[structopt(long)]
-	skip_rust_packages: bool,
+	/// Don't write the JSON report to its default path
+	#[structopt(long, conflicts_with = "report")]
+	no_report: bool,
 
-	/// Skip building rust docker images
+	/// Write a JUnit XML report of all packages and phases to this file
 	#[structopt(long)]
-	skip_rust_docker: bool,
+	junit: Option<PathBuf>,
+
+	/// Only use this Alpine release, e.g. 3.13 (can be repeated). Defaults to all releases in the
+	/// config.
+	#[structopt(long, number_of_values = 1)]
+	alpine: Vec<String>,
+
+	/// Only use this architecture, e.g. aarch64 (can be repeated). Defaults to all architectures in
+	/// the config.
+	#[structopt(long, number_of_values = 1)]
+	arch: Vec<String>,
+
+	#[structopt(subcommand)]
+	cmd: Command
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+	/// Update the configuration file if a newer rust, crate or LLVM version was found
+	UpdateConfig,
+
+	/// Check the configuration file for mistakes without building anything
+	Validate,
+
+	/// Bump the pkgrel of all packages whose APKBUILD changed since they were built, so that they
+	/// are rebuilt by the next run
+	BumpPkgrel,
+
+	/// Remove the rust versions that exceed the retention policy from the configuration file and
+	/// delete their packages from the repository
+	Prune {
+		#[structopt(flatten)]
+		server: ServerArgs,
+
+		/// Only print the rust versions and files that would be removed
+		#[structopt(long)]
+		dry_run: bool
+	},
+
+	/// Download the repository and update its metadata
+	Sync {
+		/// Upload the metadata
+		#[structopt(short = "m", long)]
+		upload_metadata: bool
+	},
+
+	/// Only print the packages that would be updated and render their templates, without
+	/// downloading the repository or connecting to docker
+	Plan {
+		/// Specify the amount of parallel jobs. Defaults to the number of CPUs on the system.
+		#[structopt(short = "j", long)]
+		jobs: Option<u16>,
+
+		#[structopt(flatten)]
+		pkgs: PackageArgs
+	},
 
-	/// Upload the metadata
-	#[structopt(short = "m", long)]
-	upload_metadata: bool,
+	/// Write the docker build contexts of a package for every selected Alpine release and
+	/// architecture into a directory, so that its build can be reproduced with plain `docker build`
+	Render {
+		/// The package to render, e.g. 1.42, stable or llvm11
+		#[structopt(name = "PACKAGE")]
+		package: String,
 
-	/// Upload the built packages to the repository
-	#[structopt(short = "p", long)]
-	upload_packages: bool,
+		/// Directory to write the build contexts to. If more than one Alpine release or architecture is
+		/// selected, the build contexts of each are written to a {alpine}/{arch} subdirectory.
+		#[structopt(short, long)]
+		output: PathBuf,
 
-	/// Upload the built docker images to the registry
-	#[structopt(short = "d", long)]
-	upload_docker: bool,
+		/// Specify the amount of parallel jobs. Defaults to the number of CPUs on the system.
+		#[structopt(short = "j", long)]
+		jobs: Option<u16>,
 
+		/// Also write the build contexts of the docker images
+		#[structopt(long)]
+		images: bool,
+
+		/// Write a placeholder file instead of omitting the private key
+		#[structopt(long)]
+		privkey_placeholder: bool
+	},
+
+	/// Build all outdated or the requested packages without testing or uploading them
+	Build {
+		#[structopt(flatten)]
+		build: BuildArgs,
+
+		#[structopt(flatten)]
+		pkgs: PackageArgs
+	},
+
+	/// Test the packages of the requested rust versions/channels that are present in the repodir
+	Test {
+		#[structopt(flatten)]
+		server: ServerArgs,
+
+		/// Rust versions/channels to test, e.g. 1.42 or stable
+		#[structopt(name = "CHANNEL", required = true)]
+		channels: Vec<String>
+	},
+
+	/// Upload all packages in the repodir that changed since they were downloaded
+	PublishPackages,
+
+	/// Build the docker images of the requested packages and push them to the registry
+	PublishImages {
+		#[structopt(flatten)]
+		server: ServerArgs,
+
+		/// Packages whose images should be published, e.g. 1.42, stable or llvm11
+		#[structopt(name = "PACKAGE", required = true)]
+		packages: Vec<String>
+	},
+
+	/// Run all phases: update the metadata, then build, test and upload all outdated or the
+	/// requested packages and build their docker images
+	Run {
+		#[structopt(flatten)]
+		build: BuildArgs,
+
+		/// Upload the metadata, the built packages and the docker images
+		#[structopt(long)]
+		publish: bool,
+
+		#[structopt(flatten)]
+		pkgs: PackageArgs
+	}
+}
+
+#[derive(Debug, StructOpt)]
+struct ServerArgs {
 	/// Use the local docker daemon
 	#[structopt(short = "l", long)]
-	docker_local: bool,
+	local: bool,
 
 	/// Deploy a docker daemon on an upcloud server
 	#[structopt(short = "u", long)]
-	docker_upcloud: bool,
+	upcloud: bool,
 
-	/// Specify the amount of parallel jobs. Defaults to the number of CPUs on the system.
+	/// Specify the amount of parallel jobs. Defaults to the number of CPUs on the server.
 	#[structopt(short = "j", long)]
-	jobs: Option<u16>,
+	jobs: Option<u16>
+}
+
+impl ServerArgs {
+	fn kind(&self) -> anyhow::Result<ServerKind> {
+		let kind = match (self.local, self.upcloud) {
+			(true, false) => Ok(ServerKind::Local),
+			(false, true) => Ok(ServerKind::Upcloud),
+			(false, false) => Err(anyhow!("No docker daemon specified, use either --local or --upcloud")),
+			(true, true) => Err(anyhow!("Only one of --local and --upcloud may be specified"))
+		};
+		kind.kind(ErrorKind::Config)
+	}
+}
+
+#[derive(Debug, StructOpt)]
+struct BuildArgs {
+	#[structopt(flatten)]
+	server: ServerArgs,
+
+	/// Specify the amount of packages that are built in parallel. The jobs are split evenly
+	/// between all packages that are being built.
+	#[structopt(long, default_value = "1")]
+	parallel: u16,
 
-	/// Rust versions/channels to exclude, e.g. 1.42 or stable (optional)
+	/// Keep updating the remaining packages after a package failed. Packages that depend on a
+	/// failed package are skipped.
+	#[structopt(long)]
+	keep_going: bool
+}
+
+#[derive(Debug, StructOpt)]
+struct PackageArgs {
+	/// Packages to exclude, e.g. 1.42, stable or llvm11 (optional)
 	#[structopt(long)]
 	ignore: Vec<String>,
 
-	/// Rust versions/channels to build, e.g. 1.42 or stable (optional)
-	#[structopt(name = "CHANNEL")]
-	channels: Vec<String>
+	/// Packages to update, e.g. 1.42, stable or llvm11. Defaults to all outdated packages.
+	#[structopt(name = "PACKAGE")]
+	packages: Vec<String>
 }
 
+impl PackageArgs {
+	fn is_requested(&self, pkg: &Packagelike<'_>) -> bool {
+		self.packages.iter().any(|name| name == &pkg.name())
+	}
+
+	fn is_ignored(&self, pkg: &Packagelike<'_>) -> bool {
+		self.ignore.iter().any(|ignore| ignore == &pkg.name())
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
 enum Packagelike<'a> {
 	LLVM(&'a PackageLLVM),
 	Rust { channel: &'a str },
-	Crate(&'a PackageCrate)
+	Crate(&'a PackageCrate),
+	Custom(&'a PackageCustom)
 }
 
 impl<'a> PartialEq for Packagelike<'a> {
@@ -132,6 +298,10 @@ impl<'a> PartialEq for Packagelike<'a> {
 			Self::Crate(krate) => match other {
 				Self::Crate(other_krate) => krate.crate_name == other_krate.crate_name,
 				_ => false
+			},
+			Self::Custom(custom) => match other {
+				Self::Custom(other_custom) => custom.pkgname == other_custom.pkgname,
+				_ => false
 			}
 		}
 	}
@@ -139,274 +309,939 @@ impl<'a> PartialEq for Packagelike<'a> {
 
 impl<'a> Eq for Packagelike<'a> {}
 
-impl<'a> PartialOrd for Packagelike<'a> {
-	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		Some(self.cmp(other))
+impl<'a> Packagelike<'a> {
+	fn name(&'a self) -> Cow<'a, str> {
+		match self {
+			Self::LLVM(llvm) => llvm.pkgname().into(),
+			Self::Rust { channel } => (*channel).into(),
+			Self::Crate(krate) => krate.pkgname().into(),
+			Self::Custom(custom) => custom.pkgname.as_str().into()
+		}
 	}
-}
 
-impl<'a> Ord for Packagelike<'a> {
-	fn cmp(&self, other: &Self) -> Ordering {
+	/// Return the key of the .apk file of this package inside the repository.
+	fn apk_key(&self, config: &Config) -> String {
 		match self {
-			Self::LLVM(llvm) => match other {
-				Self::LLVM(other_llvm) => llvm.pkgver.cmp(&other_llvm.pkgver),
-				Self::Rust { .. } => Ordering::Less,
-				Self::Crate(_) => Ordering::Less
-			},
-			Self::Rust { channel } => match other {
-				Self::LLVM(_) => Ordering::Greater,
-				Self::Rust { channel: other_channel } => channel.cmp(other_channel),
-				Self::Crate(_) => Ordering::Less
+			Self::LLVM(llvm) => build::packages::apk_key(config, *llvm),
+			Self::Rust { channel } => build::rust::apk_key(config, channel),
+			Self::Crate(krate) => build::packages::apk_key(config, *krate),
+			Self::Custom(custom) => build::packages::apk_key(config, *custom)
+		}
+	}
+
+	/// Return the docker tags that are pushed for this package.
+	fn docker_tags(&self, config: &Config) -> Vec<String> {
+		match self {
+			Self::LLVM(llvm) => build::packages::docker_tags(config, *llvm),
+			Self::Rust { channel } => {
+				let (tags, minimal_tags) = build::rust::docker_tags(config, channel);
+				tags.into_iter().chain(minimal_tags).collect()
 			},
-			Self::Crate(krate) => match other {
-				Self::LLVM(_) => Ordering::Greater,
-				Self::Rust { .. } => Ordering::Greater,
-				Self::Crate(other_krate) => krate.crate_name.cmp(&other_krate.crate_name)
-			}
+			Self::Crate(krate) => build::packages::docker_tags(config, *krate),
+			Self::Custom(custom) => build::packages::docker_tags(config, *custom)
 		}
 	}
-}
 
-impl<'a> Packagelike<'a> {
-	fn name(&'a self) -> Cow<'a, str> {
+	fn render_apkbuild(&self, config: &Config) -> Result<String, askama::Error> {
 		match self {
-			Self::LLVM(llvm) => llvm.pkgname().into(),
-			Self::Rust { channel } => (*channel).into(),
-			Self::Crate(krate) => krate.pkgname().into()
+			Self::LLVM(llvm) => llvm.render_apkbuild(config),
+			Self::Rust { channel } => config.rust_apkbuild(channel).render(),
+			Self::Crate(krate) => krate.render_apkbuild(config),
+			Self::Custom(custom) => custom.render_apkbuild(config)
 		}
 	}
 
-	fn is_up_to_date<'b, 'fut>(&self, repodir: &'b Path, config: &'b Config) -> impl Future<Output = bool> + 'fut
-	where
-		'a: 'fut,
-		'b: 'fut
-	{
+	/// Return the hash of the current APKBUILD of this package, see [build::apkbuild_hash].
+	fn apkbuild_hash(&self, config: &Config) -> anyhow::Result<String> {
+		let apkbuild = self
+			.render_apkbuild(config)
+			.with_context(|| format!("Failed to render APKBUILD of {}", self.name()))?;
+		let files = match self {
+			Self::Custom(custom) => custom.files(),
+			_ => &[]
+		};
+		Ok(build::apkbuild_hash(&apkbuild, files))
+	}
+
+	/// Return whether the APKBUILD of this package changed since the package in the repodir was
+	/// built. Packages without a recorded hash never count as changed.
+	async fn is_apkbuild_changed(&self, repodir: &Path, config: &Config) -> anyhow::Result<bool> {
+		let recorded = build::read_apkbuild_hash(repodir, &self.apk_key(config)).await?;
+		Ok(match recorded {
+			Some(hash) => hash != self.apkbuild_hash(config)?,
+			None => false
+		})
+	}
+
+	fn is_up_to_date<'b>(&self, repodir: &'b Path, config: &Config) -> impl Future<Output = anyhow::Result<bool>> + 'b {
+		let key = self.apk_key(config);
+		async move { build::up_to_date(repodir, &key).await }
+	}
+
+	/// Return the build context of the image that builds the package.
+	async fn abuild_context(&self, config: &Config, jobs: u16, privkey: PrivKey) -> anyhow::Result<BuildContext> {
 		match self {
-			Self::LLVM(llvm) => build::packages::up_to_date(repodir, config, *llvm).boxed(),
-			Self::Rust { channel } => build::rust::up_to_date(repodir, config, channel).boxed(),
-			Self::Crate(krate) => build::packages::up_to_date(repodir, config, *krate).boxed()
+			Self::LLVM(llvm) => build::packages::abuild_context(config, *llvm, jobs, privkey).await,
+			Self::Rust { channel } => build::rust::abuild_context(config, channel, jobs, privkey).await,
+			Self::Crate(krate) => build::packages::abuild_context(config, *krate, jobs, privkey).await,
+			Self::Custom(custom) => build::packages::abuild_context(config, *custom, jobs, privkey).await
 		}
 	}
 
+	/// Return the build contexts of all docker images of the package, together with a name for
+	/// each image.
+	async fn image_contexts(&self, config: &Config) -> anyhow::Result<Vec<(&'static str, BuildContext)>> {
+		Ok(match self {
+			Self::LLVM(llvm) => build::packages::image_context(config, *llvm)
+				.await?
+				.map(|context| ("image", context))
+				.into_iter()
+				.collect(),
+			Self::Rust { channel } => {
+				let (minimal, default) = build::rust::image_contexts(config, channel).await?;
+				vec![("minimal", minimal), ("default", default)]
+			},
+			Self::Crate(krate) => build::packages::image_context(config, *krate)
+				.await?
+				.map(|context| ("image", context))
+				.into_iter()
+				.collect(),
+			Self::Custom(custom) => build::packages::image_context(config, *custom)
+				.await?
+				.map(|context| ("image", context))
+				.into_iter()
+				.collect()
+		})
+	}
+
 	async fn build_package(&self, repomount: &str, docker: &Docker, config: &Config, jobs: u16) -> anyhow::Result<()> {
 		match self {
 			Self::LLVM(llvm) => build::packages::build_package(repomount, docker, config, *llvm, jobs).await,
 			Self::Rust { channel } => build::rust::build_package(repomount, docker, config, channel, jobs).await,
-			Self::Crate(krate) => build::packages::build_package(repomount, docker, config, *krate, jobs).await
+			Self::Crate(krate) => build::packages::build_package(repomount, docker, config, *krate, jobs).await,
+			Self::Custom(custom) => build::packages::build_package(repomount, docker, config, *custom, jobs).await
 		}
 	}
 
-	async fn build_and_upload_docker(&self, docker: &Docker, config: &Config, upload_docker: bool) -> anyhow::Result<()> {
+	async fn build_docker(&self, docker: &Docker, config: &Config) -> anyhow::Result<()> {
 		match self {
-			Self::LLVM(llvm) => build::packages::build_and_upload_docker(docker, config, *llvm, upload_docker).await,
-			Self::Rust { channel } => build::rust::build_and_upload_docker(docker, config, channel, upload_docker).await,
-			Self::Crate(krate) => build::packages::build_and_upload_docker(docker, config, *krate, upload_docker).await
+			Self::LLVM(llvm) => build::packages::build_docker(docker, config, *llvm).await,
+			Self::Rust { channel } => build::rust::build_docker(docker, config, channel).await,
+			Self::Crate(krate) => build::packages::build_docker(docker, config, *krate).await,
+			Self::Custom(custom) => build::packages::build_docker(docker, config, *custom).await
 		}
 	}
+
+	/// Push all docker tags of this package to the registry.
+	async fn push_docker(&self, docker: &Docker, config: &Config) -> anyhow::Result<()> {
+		for tag in self.docker_tags(config) {
+			docker::docker_push(docker, &config.deploy.registry, &tag).await?;
+		}
+		Ok(())
+	}
 }
 
-#[tokio::main]
-async fn main() {
-	let args = Args::from_args();
-	pretty_env_logger::formatted_timed_builder()
-		.filter_module("alpine_rust", match args.verbose {
-			0 => LevelFilter::Info,
-			1 => LevelFilter::Debug,
-			_ => LevelFilter::Trace
-		})
-		.init();
-	debug!("Arguments: {:?}", args);
+/// The phases that are run for every package after it was built.
+#[derive(Clone, Copy, Debug)]
+struct Phases {
+	/// Test the package if it is a rust package.
+	test: bool,
+	/// Upload the package, its metadata and the docker images.
+	publish: bool,
+	/// Build the docker images.
+	images: bool
+}
 
-	if args.update_config {
-		config::update_config(&args.config, args.cache.as_ref()).await;
+impl Phases {
+	/// Return the phases the package needs to finish.
+	fn required(&self, pkg: &Packagelike<'_>) -> Vec<Phase> {
+		let mut required = vec![Phase::Built];
+		if self.test && matches!(pkg, Packagelike::Rust { .. }) {
+			required.push(Phase::Tested);
+		}
+		if self.publish {
+			required.push(Phase::PackagesUploaded);
+		}
+		if self.images {
+			required.push(Phase::DockerBuilt);
+			if self.publish {
+				required.push(Phase::DockerPushed);
+			}
+		}
+		required
 	}
+}
 
-	info!("Reading config.toml");
-	let mut config_file = File::open(&args.config).await.expect("Unable to find config file");
-	let mut config_buf = Vec::<u8>::new();
-	config_file
-		.read_to_end(&mut config_buf)
+async fn read_config(path: &Path) -> anyhow::Result<Config> {
+	info!("Reading {}", path.display());
+	let buf = fs::read(path)
+		.await
+		.context("Unable to read config file")
+		.kind(ErrorKind::Config)?;
+	let mut config: Config = toml::from_slice(&buf)
+		.context("Invalid syntax in config file")
+		.kind(ErrorKind::Config)?;
+	config.alpine.version = config
+		.alpine
+		.versions
+		.first()
+		.cloned()
+		.ok_or_else(|| anyhow!("No alpine versions specified in config file"))
+		.kind(ErrorKind::Config)?;
+	config.alpine.arch = config
+		.alpine
+		.arches
+		.first()
+		.cloned()
+		.ok_or_else(|| anyhow!("No architectures specified in config file"))
+		.kind(ErrorKind::Config)?;
+	if let Some(arch) = config.alpine.arches.iter().find(|arch| config::docker_platform(arch).is_none()) {
+		return Err(anyhow!("Unsupported architecture {} in config file", arch)).kind(ErrorKind::Config);
+	}
+	config
+		.patches
+		.load()
 		.await
-		.expect("Unable to read config file");
-	drop(config_file);
-	let config: Config = toml::from_slice(&config_buf).expect("Invalid syntax in config file");
+		.context("Failed to load the patches")
+		.kind(ErrorKind::Config)?;
+	if let Some(krate) = config.packages.crates.iter().find(|krate| krate.git.is_some() && krate.rev.is_none()) {
+		return Err(anyhow!("Crate {} is built from git but does not specify a rev", krate.crate_name))
+			.kind(ErrorKind::Config);
+	}
+	for custom in &mut config.packages.custom {
+		custom
+			.load()
+			.await
+			.with_context(|| format!("Failed to load the templates and files of {}", custom.pkgname))
+			.kind(ErrorKind::Config)?;
+	}
+	Ok(config)
+}
 
-	// download the repository
-	let (_repotmp, repodir) = match &args.repodir {
+/// The config and the build graph of one Alpine release and architecture.
+struct Release<'a> {
+	config: &'a Config,
+	graph: BuildGraph<'a>
+}
+
+impl<'a> Release<'a> {
+	fn new(config: &'a Config) -> anyhow::Result<Self> {
+		// determine the order in which packages need to be built
+		let graph = BuildGraph::new(config)
+			.context("Invalid package dependencies")
+			.kind(ErrorKind::Config)?;
+		Ok(Self { config, graph })
+	}
+
+	fn alpine(&self) -> &'a Alpine {
+		&self.config.alpine
+	}
+}
+
+/// Return the configs of the Alpine releases and architectures requested on the command line, or
+/// of all releases and architectures.
+fn alpine_configs(args: &Args, config: &Config) -> anyhow::Result<Vec<Config>> {
+	if let Some(version) = args.alpine.iter().find(|version| !config.alpine.versions.contains(version)) {
+		return Err(anyhow!("Alpine {} is not specified in the config", version)).kind(ErrorKind::Config);
+	}
+	if let Some(arch) = args.arch.iter().find(|arch| !config.alpine.arches.contains(arch)) {
+		return Err(anyhow!("Architecture {} is not specified in the config", arch)).kind(ErrorKind::Config);
+	}
+	Ok(config
+		.alpine
+		.targets()
+		.filter(|(version, _)| args.alpine.is_empty() || args.alpine.contains(version))
+		.filter(|(_, arch)| args.arch.is_empty() || args.arch.contains(arch))
+		.map(|(version, arch)| config.for_alpine(version, arch))
+		.collect())
+}
+
+/// Return the repodir, which is a temporary directory unless one was specified on the command line.
+fn repodir(args: &Args) -> anyhow::Result<(Option<TempDir>, PathBuf)> {
+	Ok(match &args.repodir {
 		Some(repodir) => (None, repodir.to_owned()),
 		None => {
-			let repotmp = tempdir().expect("Failed to create tempdir");
+			let repotmp = tempdir().context("Failed to create tempdir")?;
 			let repodir = repotmp.path().to_owned();
 			(Some(repotmp), repodir)
 		}
-	};
-	repo::download(&repodir).await.expect("Failed to download repo");
+	})
+}
 
-	// create the repo dir if it does not exist yet
-	let x86_64 = repodir.join(format!("{}/alpine-rust/x86_64", config.alpine.version));
-	debug!("Creating directory {}", x86_64.display());
-	if let Err(err) = fs::create_dir_all(&x86_64).await {
-		warn!("Unable to create {}: {}", x86_64.display(), err);
+/// Create the directories of our packages for all Alpine releases and architectures inside the
+/// repodir if they do not exist yet.
+async fn create_repo_dirs(config: &Config, repodir: &Path) {
+	for (version, arch) in config.alpine.targets() {
+		let dir = repodir.join(config::repo_dir(version, arch));
+		debug!("Creating directory {}", dir.display());
+		if let Err(err) = fs::create_dir_all(&dir).await {
+			warn!("Unable to create {}: {}", dir.display(), err);
+		}
 	}
+}
 
-	// update the metadata
-	if args.skip_metadata {
-		info!("Skipping metadata update");
-	} else {
-		metadata::update(&config, &repodir, args.upload_metadata).await;
+async fn download_repo(config: &Config, repodir: &Path) -> anyhow::Result<()> {
+	let storage = repo::open(&config.deploy.repo).await.kind(ErrorKind::Upstream)?;
+	repo::download(&*storage, repodir)
+		.await
+		.context("Failed to download repo")
+		.kind(ErrorKind::Upstream)?;
+	create_repo_dirs(config, repodir).await;
+	Ok(())
+}
+
+/// Download only the index of the repository, the packages are downloaded when they are needed
+/// to bootstrap a build and are otherwise served by caddy.
+async fn download_repo_index(config: &Config, repodir: &Path) -> anyhow::Result<()> {
+	let storage = repo::open(&config.deploy.repo).await.kind(ErrorKind::Upstream)?;
+	repo::download_index(&*storage, config, repodir)
+		.await
+		.context("Failed to download repo index")
+		.kind(ErrorKind::Upstream)?;
+	create_repo_dirs(config, repodir).await;
+	Ok(())
+}
+
+/// Download the packages that the outdated packages depend on but that are not rebuilt, so that
+/// they can be installed during the build.
+async fn download_dependencies(targets: &Targets<'_, '_>, repodir: &Path, storage: &dyn Storage) -> anyhow::Result<()> {
+	for (release, pkgs) in targets {
+		let keys = pkgs
+			.iter()
+			.flat_map(|pkg| release.graph.dependencies(pkg))
+			.filter(|dep| !pkgs.contains(dep))
+			.map(|dep| dep.apk_key(release.config))
+			.unique()
+			.collect::<Vec<_>>();
+		repo::download_packages(storage, release.config, repodir, &keys).await?;
+	}
+	Ok(())
+}
+
+/// Make sure that all packages named on the command line exist in the config.
+fn check_names<'a>(graph: &BuildGraph<'_>, names: impl IntoIterator<Item = &'a String>) -> anyhow::Result<()> {
+	for name in names {
+		if !graph.packages().any(|pkg| &pkg.name() == name) {
+			return Err(anyhow!("Unknown package {}", name)).kind(ErrorKind::Config);
+		}
+	}
+	Ok(())
+}
+
+/// Load the state file that belongs to the repodir, or state.json if no repodir was specified, and
+/// forget about all packages that are no longer part of the config of any Alpine release and
+/// architecture.
+async fn load_state(args: &Args, config: &Config) -> anyhow::Result<State> {
+	let path = args
+		.repodir
+		.as_deref()
+		.and_then(State::path)
+		.unwrap_or_else(|| PathBuf::from("state.json"));
+	let state = State::load(path)
+		.await
+		.kind(ErrorKind::Config)?;
+	let mut keys = BTreeSet::new();
+	for (version, arch) in config.alpine.targets() {
+		let config = config.for_alpine(version, arch);
+		let release = Release::new(&config)?;
+		keys.extend(release.graph.packages().map(|pkg| pkg.apk_key(&config)));
 	}
+	state.retain(&keys).await?;
+	Ok(state)
+}
+
+/// Return all packages that are missing from the repodir or have unfinished phases, or, if
+/// packages were requested, only those of the requested packages. Requested packages that have
+/// finished all phases are started from scratch. Ignored packages are never returned.
+async fn outdated_packages<'a>(
+	config: &Config,
+	graph: &BuildGraph<'a>,
+	repodir: &Path,
+	state: &State,
+	phases: Phases,
+	args: &PackageArgs
+) -> anyhow::Result<Vec<Packagelike<'a>>> {
+	check_names(graph, args.packages.iter().chain(&args.ignore))?;
 
-	// search for versions that need to be updated
 	debug!("Determining packages that needs updates");
-	let config_ver_iter = config
-		.rust
-		.keys()
-		.map(|channel| Packagelike::Rust {
-			channel: channel.as_str()
+	let mut pkgs = Vec::new();
+	for pkg in graph.packages() {
+		if args.is_ignored(&pkg) || (!args.packages.is_empty() && !args.is_requested(&pkg)) {
+			continue;
+		}
+
+		let key = pkg.apk_key(config);
+		let unfinished = match state.phases(&key).await {
+			Some(finished) => phases.required(&pkg).iter().any(|phase| !finished.contains(phase)),
+			None => false
+		};
+		if !pkg.is_up_to_date(repodir, config).await? {
+			state.reset(&key).await?;
+			pkgs.push(pkg);
+		} else if unfinished {
+			info!("Resuming unfinished package {}", pkg.name());
+			pkgs.push(pkg);
+		} else if args.is_requested(&pkg) {
+			state.reset(&key).await?;
+			pkgs.push(pkg);
+		} else if pkg.is_apkbuild_changed(repodir, config).await? {
+			warn!(
+				"The APKBUILD of {} for {} changed since it was built, run bump-pkgrel to rebuild it",
+				pkg.name(),
+				config.alpine
+			);
+		}
+	}
+	Ok(pkgs)
+}
+
+async fn plan(config: &Config, graph: &BuildGraph<'_>, jobs: Option<u16>, args: &PackageArgs) -> anyhow::Result<()> {
+	check_names(graph, args.packages.iter().chain(&args.ignore))?;
+
+	let storage = repo::open(&config.deploy.repo).await.kind(ErrorKind::Upstream)?;
+	let listing = repo::list(&*storage).await.context("Failed to list repository")?;
+	let pkgs = graph
+		.packages()
+		.filter_map(|pkg| {
+			if !args.packages.is_empty() {
+				return if args.is_requested(&pkg) {
+					Some((pkg, Reason::Requested))
+				} else {
+					None
+				};
+			}
+			let key = pkg.apk_key(config);
+			if listing.contains(&key) {
+				None
+			} else {
+				Some((pkg, Reason::Missing(key)))
+			}
 		})
-		.chain(config.packages.llvm.iter().map(|llvm| Packagelike::LLVM(llvm)))
-		.chain(config.packages.crates.iter().map(|krate| Packagelike::Crate(krate)));
-	let mut pkg_updates = if args.channels.is_empty() {
-		stream::iter(config_ver_iter)
-			.filter(|pkg| pkg.is_up_to_date(&repodir, &config).map(|up_to_date| !up_to_date))
-			.collect::<BTreeSet<_>>()
+		.filter(|(pkg, _)| !args.is_ignored(pkg))
+		.collect::<Vec<_>>();
+	let jobs = jobs.unwrap_or_else(|| num_cpus::get() as u16);
+	plan::print_plan(config, graph, &pkgs, jobs)
+}
+
+/// Write the build contexts of the package into subdirectories of the output directory.
+async fn render(
+	config: &Config,
+	graph: &BuildGraph<'_>,
+	name: &str,
+	output: &Path,
+	jobs: Option<u16>,
+	images: bool,
+	privkey: PrivKey
+) -> anyhow::Result<()> {
+	let pkg = graph
+		.packages()
+		.find(|pkg| pkg.name() == name)
+		.ok_or_else(|| anyhow!("Unknown package {}", name))
+		.kind(ErrorKind::Config)?;
+	let jobs = jobs.unwrap_or_else(|| num_cpus::get() as u16);
+
+	let mut contexts = vec![("abuild", pkg.abuild_context(config, jobs, privkey).await?)];
+	if images {
+		contexts.extend(pkg.image_contexts(config).await?);
+	}
+	for (dir, context) in contexts {
+		let dir = output.join(dir);
+		context
+			.write_to(&dir)
 			.await
-	} else {
-		config_ver_iter
-			.filter(|channel| args.channels.iter().any(|ch| ch == &channel.name()))
-			.collect::<BTreeSet<_>>()
-	};
-	if !args.ignore.is_empty() {
-		let ignore = args.ignore.iter().map(|ignore| ignore.as_str()).collect::<BTreeSet<_>>();
-		pkg_updates = pkg_updates
-			.into_iter()
-			.filter(|pkg| {
-				let name: &str = &pkg.name();
-				!ignore.contains(name)
-			})
-			.collect();
+			.with_context(|| format!("Failed to write build context to {}", dir.display()))?;
+		info!("Wrote build context to {}", dir.display());
+	}
+
+	if privkey == PrivKey::Omit {
+		info!(
+			"The private key was omitted, copy {} into {} before building",
+			config.alpine.privkey,
+			output.join("abuild").display()
+		);
+	}
+	Ok(())
+}
+
+/// The outdated packages of every Alpine release and architecture.
+type Targets<'r, 'a> = Vec<(&'r Release<'a>, Vec<Packagelike<'a>>)>;
+
+/// Return the outdated packages of every Alpine release and architecture, see [outdated_packages].
+async fn outdated_targets<'r, 'a>(
+	releases: &'r [Release<'a>],
+	repodir: &Path,
+	state: &State,
+	phases: Phases,
+	args: &PackageArgs
+) -> anyhow::Result<Targets<'r, 'a>> {
+	let mut targets = Vec::with_capacity(releases.len());
+	for release in releases {
+		let pkgs = outdated_packages(release.config, &release.graph, repodir, state, phases, args).await?;
+		targets.push((release, pkgs));
 	}
+	Ok(targets)
+}
 
+/// Build the packages of every Alpine release and architecture and run the requested phases for
+/// each of them.
+async fn update_packages(
+	config: &Config,
+	targets: &Targets<'_, '_>,
+	repodir: &Path,
+	state: &State,
+	report: &Report,
+	build: &BuildArgs,
+	phases: Phases
+) -> anyhow::Result<()> {
 	// if everything is up to date, simply exit
-	if pkg_updates.is_empty() {
+	if targets.iter().all(|(_, pkgs)| pkgs.is_empty()) {
 		info!("Everything is up to date");
-		return;
+		return Ok(());
+	}
+	for (release, pkgs) in targets.iter().filter(|(_, pkgs)| !pkgs.is_empty()) {
+		let pkgs_str = pkgs.iter().map(|pkg| pkg.name()).join(", ");
+		info!("The following packages will be updated for {}: {}", release.alpine(), pkgs_str);
 	}
-	let pkg_updates_str = pkg_updates.iter().map(|pkg| pkg.name()).join(", ");
-	info!("The following rust versions will be updated: {}", pkg_updates_str);
 
-	// connect to docker - create a server
-	let mut server = if args.docker_local {
-		Either::Left(LocalServer::new(&config, &repodir))
-	} else if args.docker_upcloud {
-		Either::Right(UpcloudServer::create(&config).await.expect("Failed to create UpCloud server"))
-	} else {
-		error!("Unable to connect to docker daemon: No docker daemon specified");
-		exit(1);
-	};
-
-	// connect to docker - install the server
-	if let Err(err) = server.install(&config, &repodir).await {
-		error!("Failed to install server: {}", err);
-		server.destroy().await.expect("Failed to destroy the server");
-		exit(1);
-	}
-
-	// connect to docker
-	let docker = match server.connect_to_docker() {
-		Ok(docker) => docker,
-		Err(err) => {
-			error!("Failed to connect to docker: {}", err);
-			server.destroy().await.expect("Failed to destroy the server");
-			exit(1);
-		}
-	};
-	let docker = Arc::new(docker);
-	info!("Connected to docker daemon");
-
-	// determine the docker environment
-	debug!("Inspecting docker environment");
-	let repomount = server.repomount(&repodir);
-	let jobs = args.jobs.unwrap_or_else(|| server.cores());
-	let cidr_v6 = server.cidr_v6();
-
-	// start our local caddy server
-	if let Err(err) = docker::build_caddy(&docker, &config).await {
-		error!("Unable to build caddy image: {}", err);
-		exit(1);
-	}
-	let caddy = match docker::start_caddy(&docker, &repomount).await {
-		Ok(caddy) => caddy,
-		Err(err) => {
-			error!("Unable to start caddy container: {}", err);
-			if let Some(cause) = err.source() {
-				error!("Cause: {}", cause);
+	let kind = build.server.kind()?;
+	let storage = repo::open(&config.deploy.repo).await.kind(ErrorKind::Upstream)?;
+	download_dependencies(targets, repodir, &*storage)
+		.await
+		.context("Failed to download dependencies")
+		.kind(ErrorKind::Upstream)?;
+
+	let session = Session::start(config, repodir, kind, true).await?;
+	let jobs = build.server.jobs.unwrap_or(session.cores);
+	let parallel = build.parallel.max(1);
+	let build_jobs = (jobs / parallel).max(1);
+	if parallel > 1 {
+		info!("Building up to {} packages in parallel with {} jobs each", parallel, build_jobs);
+	}
+
+	let res = signal::interruptible(async {
+		let mut res = Ok(());
+		for (release, pkgs) in targets.iter().filter(|(_, pkgs)| !pkgs.is_empty()) {
+			let alpine = &release.config.alpine;
+			if res.is_err() && !build.keep_going {
+				warn!("Skipping update of {} because of previous errors", release.alpine());
+				for pkg in pkgs {
+					skip_package(report, alpine, pkg, phases, Status::NotRun, "not started because of previous errors");
+				}
+				continue;
 			}
-			exit(1);
+			info!("Updating packages for {}", release.alpine());
+			let release_res = release
+				.graph
+				.execute(
+					pkgs,
+					parallel.into(),
+					build.keep_going,
+					|pkg| update_package(&session, release.config, repodir, state, report, pkg, phases, build_jobs),
+					|pkg, status, reason| skip_package(report, alpine, &pkg, phases, status, reason)
+				)
+				.await;
+			res = res.and(release_res);
 		}
-	};
+		res
+	})
+	.await;
 
-	// update packages
-	for pkg in pkg_updates {
-		// build the package
-		if args.skip_rust_packages && matches!(pkg, Packagelike::Rust { .. }) {
-			info!("Skipping rust packages for {}", pkg.name())
+	res.and(session.stop().await)
+}
+
+/// Record every requested phase of a package that was not updated in the report.
+fn skip_package(report: &Report, alpine: &Alpine, pkg: &Packagelike<'_>, phases: Phases, status: Status, reason: &str) {
+	for phase in phases.required(pkg) {
+		report.skip(alpine, &pkg.name(), phase, status, reason);
+	}
+}
+
+/// The reason for skipping phases that were finished in a previous run.
+const FINISHED: &str = "finished in a previous run";
+
+/// Build a package and run the requested phases for it. Phases that were finished in a previous
+/// run are skipped.
+#[allow(clippy::too_many_arguments)]
+async fn update_package(
+	session: &Session,
+	config: &Config,
+	repodir: &Path,
+	state: &State,
+	report: &Report,
+	pkg: Packagelike<'_>,
+	phases: Phases,
+	jobs: u16
+) -> anyhow::Result<()> {
+	let docker = &session.docker;
+	let alpine = &config.alpine;
+	let name = pkg.name().into_owned();
+	let key = pkg.apk_key(config);
+	let finished = state.phases(&key).await.unwrap_or_default();
+
+	// build the package
+	if finished.contains(&Phase::Built) {
+		info!("Package {} was already built", name);
+		report.skip(alpine, &name, Phase::Built, Status::Skipped, FINISHED);
+	} else {
+		report
+			.phase(alpine, &name, Phase::Built, async {
+				pkg.build_package(&session.repomount, docker, config, jobs)
+					.await
+					.with_context(|| format!("Failed to build package {}", name))?;
+				// abuild doesn't update the index since parallel builds would overwrite each other's index,
+				// and it would only index the packages that are present in the repodir
+				session
+					.rebuild_index(config, repo::has_remote_index(config, repodir))
+					.await
+					.context("Failed to rebuild the index")?;
+				session
+					.download_repo_changes(config, repodir)
+					.await
+					.context("Failed to download changes")?;
+				build::write_apkbuild_hash(repodir, &key, &pkg.apkbuild_hash(config)?).await
+			})
+			.await?;
+		state.finish(&key, Phase::Built).await?;
+	}
+
+	// test the package if it was a rust package
+	if let (true, Packagelike::Rust { channel }) = (phases.test, pkg) {
+		if finished.contains(&Phase::Tested) {
+			info!("Package {} was already tested", name);
+			report.skip(alpine, &name, Phase::Tested, Status::Skipped, FINISHED);
 		} else {
-			if let Err(err) = pkg.build_package(&repomount, &docker, &config, jobs).await {
-				error!("Failed to build package {}: {}", pkg.name(), err);
-				if let Err(err) = caddy.stop(&docker).await {
-					error!("Unable to stop caddy: {}", err);
-				}
-				server.destroy().await.expect("Failed to destroy the server");
-				exit(1);
-			}
+			report
+				.phase(alpine, &name, Phase::Tested, async {
+					let results = build::rust::test_package(docker.clone(), &session.cidr_v6, config, channel).await?;
+					report.tests(alpine, &name, &results);
+					build::rust::check_test_results(&results)
+				})
+				.await
+				.with_context(|| format!("Testing package {} failed", name))?;
+			// TODO maybe upload the package somewhere for manual inspection
+			state.finish(&key, Phase::Tested).await?;
 		}
+	}
 
-		// test the package if it was a rust package
-		if let Packagelike::Rust { channel } = pkg {
-			if let Err(err) = build::rust::test_package(docker.clone(), &cidr_v6, &config, channel).await {
-				error!("Testing package failed: {}", err);
-				// TODO maybe upload the package somewhere for manual inspection
-				if let Err(err) = caddy.stop(&docker).await {
-					error!("Unable to stop caddy: {}", err);
+	// upload the changes
+	if phases.publish && finished.contains(&Phase::PackagesUploaded) {
+		report.skip(alpine, &name, Phase::PackagesUploaded, Status::Skipped, FINISHED);
+	} else if phases.publish {
+		report
+			.phase(alpine, &name, Phase::PackagesUploaded, async {
+				session
+					.upload_repo_changes(config, repodir)
+					.await
+					.context("Failed to commit changes")
+			})
+			.await?;
+		state.finish(&key, Phase::PackagesUploaded).await?;
+	}
+
+	// build the docker images - they don't outlive the server, so they have to be
+	// rebuilt unless they were pushed
+	if phases.images && finished.contains(&Phase::DockerPushed) {
+		report.skip(alpine, &name, Phase::DockerBuilt, Status::Skipped, FINISHED);
+		report.skip(alpine, &name, Phase::DockerPushed, Status::Skipped, FINISHED);
+	} else if phases.images {
+		report
+			.phase(alpine, &name, Phase::DockerBuilt, async {
+				pkg.build_docker(docker, config)
+					.await
+					.with_context(|| format!("Failed to build docker images for {}", name))
+			})
+			.await?;
+		state.finish(&key, Phase::DockerBuilt).await?;
+
+		if phases.publish {
+			report
+				.phase(alpine, &name, Phase::DockerPushed, async {
+					pkg.push_docker(docker, config)
+						.await
+						.with_context(|| format!("Failed to push docker images for {}", name))
+				})
+				.await?;
+			state.finish(&key, Phase::DockerPushed).await?;
+		}
+	}
+
+	Ok(())
+}
+
+async fn test(
+	config: &Config,
+	releases: &[Release<'_>],
+	repodir: &Path,
+	report: &Report,
+	server: &ServerArgs,
+	channels: &[String]
+) -> anyhow::Result<()> {
+	for release in releases {
+		check_names(&release.graph, channels)?;
+	}
+	if let Some(channel) = channels.iter().find(|channel| !config.rust.contains_key(*channel)) {
+		return Err(anyhow!("{} is not a rust package", channel)).kind(ErrorKind::Config);
+	}
+
+	let session = Session::start(config, repodir, server.kind()?, true).await?;
+	let res = signal::interruptible(async {
+		let mut res = Ok(());
+		for release in releases {
+			let alpine = release.alpine();
+			for channel in channels {
+				let test_res = report
+					.phase(alpine, channel, Phase::Tested, async {
+						let results =
+							build::rust::test_package(session.docker.clone(), &session.cidr_v6, release.config, channel)
+								.await?;
+						report.tests(alpine, channel, &results);
+						build::rust::check_test_results(&results)
+					})
+					.await;
+				if let Err(err) = test_res {
+					error!("Testing package {} for {} failed: {:#}", channel, alpine, err);
+					res = Err(err).with_context(|| format!("Testing package {} for {} failed", channel, alpine));
 				}
-				server.destroy().await.expect("Failed to destroy the server");
-				exit(1);
 			}
 		}
+		res
+	})
+	.await;
 
-		// upload the changes
-		if args.upload_packages {
-			if let Err(err) = server.upload_repo_changes(&config, &repodir).await {
-				error!("Failed to commit changes: {}", err);
-				if let Err(err) = caddy.stop(&docker).await {
-					error!("Unable to stop caddy: {}", err);
-				}
-				server.destroy().await.expect("Failed to destroy the server");
-				exit(1);
+	res.and(session.stop().await)
+}
+
+async fn publish_images(
+	config: &Config,
+	releases: &[Release<'_>],
+	repodir: &Path,
+	report: &Report,
+	server: &ServerArgs,
+	names: &[String]
+) -> anyhow::Result<()> {
+	for release in releases {
+		check_names(&release.graph, names)?;
+	}
+
+	create_repo_dirs(config, repodir).await;
+	let session = Session::start(config, repodir, server.kind()?, false).await?;
+	let res = signal::interruptible(async {
+		for release in releases {
+			let alpine = release.alpine();
+			let pkgs = release
+				.graph
+				.packages()
+				.filter(|pkg| names.iter().any(|name| name == &pkg.name()));
+			for pkg in pkgs {
+				let name = pkg.name();
+				report
+					.phase(alpine, &name, Phase::DockerBuilt, async {
+						pkg.build_docker(&session.docker, release.config)
+							.await
+							.with_context(|| format!("Failed to build docker images for {}", name))
+					})
+					.await?;
+				report
+					.phase(alpine, &name, Phase::DockerPushed, async {
+						pkg.push_docker(&session.docker, release.config)
+							.await
+							.with_context(|| format!("Failed to push docker images for {}", name))
+					})
+					.await?;
 			}
 		}
+		Ok(())
+	})
+	.await;
 
-		// build the docker images if it was a rust package
-		if args.skip_rust_docker {
-			info!("Skipping rust docker images for {}", pkg.name());
-		} else {
-			if let Err(err) = pkg.build_and_upload_docker(&docker, &config, args.upload_docker).await {
-				error!("Failed to build docker images: {}", err);
-				if let Err(err) = caddy.stop(&docker).await {
-					error!("Unable to stop caddy: {}", err);
+	res.and(session.stop().await)
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+	if let Command::UpdateConfig = args.cmd {
+		return config::update_config(&args.config, args.cache.as_ref()).await;
+	}
+
+	let config = read_config(&args.config).await?;
+	match args.cmd {
+		Command::Validate => return validate::validate(&config).await,
+		// catch mistakes before a server is provisioned for hours
+		Command::Build { .. } | Command::Run { .. } => validate::validate(&config).await?,
+		_ => {}
+	}
+
+	let configs = alpine_configs(&args, &config)?;
+	let releases = configs.iter().map(Release::new).collect::<anyhow::Result<Vec<_>>>()?;
+
+	let report = Report::default();
+	let res = run_command(&args, &config, &releases, &report).await;
+	res.and(write_report(&args, &report).await)
+}
+
+async fn write_report(args: &Args, report: &Report) -> anyhow::Result<()> {
+	if let Some(path) = &args.report {
+		report.write_json(path).await?;
+	} else if !args.no_report && !report.is_empty() {
+		let path = args
+			.repodir
+			.as_deref()
+			.and_then(Report::path)
+			.unwrap_or_else(|| PathBuf::from("report.json"));
+		report.write_json(&path).await?;
+	}
+	if let Some(path) = &args.junit {
+		report.write_junit(path).await?;
+	}
+	Ok(())
+}
+
+async fn run_command(args: &Args, config: &Config, releases: &[Release<'_>], report: &Report) -> anyhow::Result<()> {
+	match &args.cmd {
+		Command::UpdateConfig | Command::Validate => Ok(()),
+
+		Command::BumpPkgrel => {
+			let (_repotmp, repodir) = repodir(args)?;
+			download_repo_index(config, &repodir).await?;
+			let mut changed = Vec::new();
+			for release in releases {
+				for pkg in release.graph.packages() {
+					if !changed.contains(&pkg)
+						&& pkg.is_up_to_date(&repodir, release.config).await?
+						&& pkg.is_apkbuild_changed(&repodir, release.config).await?
+					{
+						info!("The APKBUILD of {} for {} changed since it was built", pkg.name(), release.alpine());
+						changed.push(pkg);
+					}
 				}
-				server.destroy().await.expect("Failed to destroy the server");
-				exit(1);
 			}
+			config::bump_pkgrel(&args.config, &changed).await
+		},
+
+		Command::Prune { server, dry_run } => {
+			let versions = prune::expired_versions(config)?;
+			if versions.is_empty() {
+				info!("No rust versions exceed the retention policy");
+				return Ok(());
+			}
+			let (_repotmp, repodir) = repodir(args)?;
+			download_repo(config, &repodir).await?;
+			prune::prune(&args.config, config, &versions, &repodir, server, *dry_run).await
+		},
+
+		Command::Sync { upload_metadata } => {
+			let (_repotmp, repodir) = repodir(args)?;
+			download_repo(config, &repodir).await?;
+			metadata::update(config, &repodir, *upload_metadata).await
+		},
+
+		Command::Plan { jobs, pkgs } => {
+			let mut res = Ok(());
+			for release in releases {
+				res = res.and(plan(release.config, &release.graph, *jobs, pkgs).await);
+			}
+			res
+		},
+
+		Command::Render {
+			package,
+			output,
+			jobs,
+			images,
+			privkey_placeholder
+		} => {
+			let privkey = if *privkey_placeholder {
+				PrivKey::Placeholder
+			} else {
+				PrivKey::Omit
+			};
+			for release in releases {
+				let output = match releases.len() {
+					1 => output.to_owned(),
+					_ => output.join(&release.config.alpine.version).join(&release.config.alpine.arch)
+				};
+				render(release.config, &release.graph, package, &output, *jobs, *images, privkey).await?;
+			}
+			Ok(())
+		},
+
+		Command::Build { build, pkgs } => {
+			let (_repotmp, repodir) = repodir(args)?;
+			download_repo_index(config, &repodir).await?;
+			let state = load_state(args, config).await?;
+			let phases = Phases {
+				test: false,
+				publish: false,
+				images: false
+			};
+			let targets = outdated_targets(releases, &repodir, &state, phases, pkgs).await?;
+			update_packages(config, &targets, &repodir, &state, report, build, phases).await
+		},
+
+		Command::Test { server, channels } => {
+			let (_repotmp, repodir) = repodir(args)?;
+			download_repo_index(config, &repodir).await?;
+			test(config, releases, &repodir, report, server, channels).await
+		},
+
+		Command::PublishPackages => match &args.repodir {
+			Some(repodir) => {
+				for release in releases {
+					repo::upload_changes(release.config, repodir)
+						.await
+						.context("Failed to commit changes")
+						.kind(ErrorKind::Publish)?;
+				}
+				Ok(())
+			},
+			None => Err(anyhow!("publish-packages requires --repodir")).kind(ErrorKind::Config)
+		},
+
+		Command::PublishImages { server, packages } => {
+			let (_repotmp, repodir) = repodir(args)?;
+			publish_images(config, releases, &repodir, report, server, packages).await
+		},
+
+		Command::Run {
+			build,
+			publish,
+			pkgs
+		} => {
+			let (_repotmp, repodir) = repodir(args)?;
+			download_repo_index(config, &repodir).await?;
+			metadata::update(config, &repodir, *publish).await?;
+			let state = load_state(args, config).await?;
+			let phases = Phases {
+				test: true,
+				publish: *publish,
+				images: true
+			};
+			let targets = outdated_targets(releases, &repodir, &state, phases, pkgs).await?;
+			update_packages(config, &targets, &repodir, &state, report, build, phases).await
 		}
 	}
+}
 
-	// stop the caddy container
-	if let Err(err) = caddy.stop(&docker).await {
-		error!("Unable to stop caddy: {}", err);
+#[tokio::main]
+async fn main() {
+	let args = Args::from_args();
+	pretty_env_logger::formatted_timed_builder()
+		.filter_module("alpine_rust", match args.verbose {
+			0 => LevelFilter::Info,
+			1 => LevelFilter::Debug,
+			_ => LevelFilter::Trace
+		})
+		.init();
+	debug!("Arguments: {:?}", args);
+
+	if let Err(err) = signal::install() {
+		warn!("Unable to install signal handlers: {}", err);
 	}
 
-	// remove the server
-	server.destroy().await.expect("Failed to destroy the server");
+	if let Err(err) = run(args).await {
+		error!("{:#}", err);
+		if let Some(interrupted) = err.chain().find_map(|err| err.downcast_ref::<signal::Interrupted>()) {
+			exit(128 + interrupted.signo);
+		}
+		exit(ErrorKind::of(&err).map(ErrorKind::exit_code).unwrap_or(1));
+	}
 }
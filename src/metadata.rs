@@ -1,4 +1,8 @@
-use super::{repo, Config};
+use super::{
+	error::{ErrorKind, ErrorKindExt},
+	repo, Config
+};
+use anyhow::Context;
 use askama::Template;
 use std::path::Path;
 use tokio::{
@@ -6,25 +10,39 @@ use tokio::{
 	io::AsyncWriteExt
 };
 
-pub(super) async fn update(config: &Config, repodir: &Path, upload_metadata: bool) {
+pub(super) async fn update(config: &Config, repodir: &Path, upload_metadata: bool) -> anyhow::Result<()> {
 	info!("Updating repository metadata");
+	let storage = if upload_metadata {
+		Some(repo::open(&config.deploy.repo).await.kind(ErrorKind::Publish)?)
+	} else {
+		None
+	};
 
 	let path = repodir.join(&config.alpine.pubkey);
-	fs::copy(&config.alpine.pubkey, &path).await.expect("Unable to copy pubkey");
-	if upload_metadata {
-		repo::upload(&path, &config.alpine.pubkey)
+	fs::copy(&config.alpine.pubkey, &path)
+		.await
+		.context("Unable to copy pubkey")
+		.kind(ErrorKind::Config)?;
+	if let Some(storage) = &storage {
+		repo::upload(&**storage, &path, &config.alpine.pubkey)
 			.await
-			.expect("Failed to upload pubkey");
+			.context("Failed to upload pubkey")
+			.kind(ErrorKind::Publish)?;
 	}
 
 	let path = repodir.join("index.html");
-	let mut index_html = File::create(&path).await.expect("Unable to create index.html");
+	let mut index_html = File::create(&path).await.context("Unable to create index.html")?;
 	index_html
-		.write_all(config.index_html().render().expect("Unable to render index.html").as_bytes())
+		.write_all(config.index_html().render().context("Unable to render index.html")?.as_bytes())
 		.await
-		.expect("Unable to write index.html");
+		.context("Unable to write index.html")?;
 	drop(index_html);
-	if upload_metadata {
-		repo::upload(&path, "index.html").await.expect("Failed to upload index.html");
+	if let Some(storage) = &storage {
+		repo::upload(&**storage, &path, "index.html")
+			.await
+			.context("Failed to upload index.html")
+			.kind(ErrorKind::Publish)?;
 	}
+
+	Ok(())
 }
@@ -0,0 +1,113 @@
+use crate::{build::packages::Package, config::Config, graph::BuildGraph, Packagelike};
+use anyhow::bail;
+use askama::Template;
+use itertools::Itertools;
+use std::fmt::{self, Display};
+
+/// The reason why a package is part of the plan.
+pub enum Reason {
+	/// The package was requested on the command line.
+	Requested,
+	/// The .apk file of the package is missing from the repository.
+	Missing(String)
+}
+
+impl Display for Reason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Requested => write!(f, "requested on the command line"),
+			Self::Missing(key) => write!(f, "{} is missing from the repository", key)
+		}
+	}
+}
+
+/// Render all templates that would be used to build the package, so that any template errors
+/// show up in the plan.
+fn render(config: &Config, pkg: &Packagelike<'_>, jobs: u16) -> Result<Vec<(&'static str, String)>, askama::Error> {
+	let mut rendered = vec![("APKBUILD", pkg.render_apkbuild(config)?)];
+	match pkg {
+		Packagelike::LLVM(_) => {
+			rendered.push(("abuild.Dockerfile", config.packages_dockerfile_abuild(jobs, &[]).render()?));
+		},
+		Packagelike::Rust { channel } => {
+			rendered.push(("abuild.Dockerfile", config.rust_dockerfile_abuild(channel, jobs).render()?));
+			rendered.push(("minimal.Dockerfile", config.rust_dockerfile_minimal(channel).render()?));
+			rendered.push(("default.Dockerfile", config.rust_dockerfile_default(channel).render()?));
+		},
+		Packagelike::Crate(krate) => {
+			rendered.push(("abuild.Dockerfile", config.packages_dockerfile_abuild(jobs, &[]).render()?));
+			if let Some(dockerfile) = krate.render_dockerfile(config) {
+				rendered.push(("Dockerfile", dockerfile?));
+			}
+		},
+		Packagelike::Custom(custom) => {
+			rendered.push(("abuild.Dockerfile", config.packages_dockerfile_abuild(jobs, custom.files()).render()?));
+			if let Some(dockerfile) = custom.render_dockerfile(config) {
+				rendered.push(("Dockerfile", dockerfile?));
+			}
+		}
+	}
+	Ok(rendered)
+}
+
+fn bootstrap(config: &Config, graph: &BuildGraph<'_>, pkg: &Packagelike<'_>) -> String {
+	let mut deps = graph.dependencies(pkg).map(|dep| dep.name().into_owned()).collect::<Vec<_>>();
+	if let Packagelike::Rust { channel } = pkg {
+		let rust = &config.rust[*channel];
+		if rust.bootsys {
+			let sysver = rust.sysver.as_deref().unwrap_or(&config.alpine.version);
+			deps.insert(0, format!("rust {} from alpine {}", rust.bootver, sysver));
+		}
+	}
+	if deps.is_empty() {
+		"none".to_owned()
+	} else {
+		deps.join(", ")
+	}
+}
+
+/// Print the plan, i.e. all packages that would be updated and what would happen to them. Returns
+/// an error if any of the templates failed to render.
+pub fn print_plan(
+	config: &Config,
+	graph: &BuildGraph<'_>,
+	pkgs: &[(Packagelike<'_>, Reason)],
+	jobs: u16
+) -> anyhow::Result<()> {
+	if pkgs.is_empty() {
+		println!("Everything is up to date for {}", config.alpine);
+		return Ok(());
+	}
+
+	let mut failed = 0;
+	println!("The following packages would be updated for {}, in this order:", config.alpine);
+	for (pkg, reason) in pkgs {
+		println!();
+		println!("{}", pkg.name());
+		println!("  reason:      {}", reason);
+		println!("  depends on:  {}", bootstrap(config, graph, pkg));
+		match render(config, pkg, jobs) {
+			Ok(rendered) => {
+				for (name, content) in &rendered {
+					debug!("Rendered {} for {}:\n{}", name, pkg.name(), content);
+				}
+				println!("  renders:     {}", rendered.iter().map(|(name, _)| name).join(", "));
+			},
+			Err(err) => {
+				println!("  renders:     FAILED: {}", err);
+				failed += 1;
+			}
+		}
+		let tags = pkg.docker_tags(config);
+		if tags.is_empty() {
+			println!("  docker tags: none");
+		} else {
+			println!("  docker tags: {}", tags.join(", "));
+		}
+	}
+
+	if failed > 0 {
+		bail!("{} out of {} packages failed to render", failed, pkgs.len());
+	}
+	Ok(())
+}
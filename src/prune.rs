@@ -0,0 +1,160 @@
+use crate::{
+	config::{self, Config},
+	error::{ErrorKind, ErrorKindExt},
+	repo,
+	session::Session,
+	signal, ServerArgs
+};
+use anyhow::{anyhow, Context};
+use futures_util::StreamExt;
+use itertools::Itertools;
+use regex::Regex;
+use std::{
+	io,
+	path::{Path, PathBuf}
+};
+use tokio::fs;
+
+lazy_static! {
+	static ref VERSION_REGEX: Regex = Regex::new(r"^1\.(?P<minor>\d+)$").unwrap();
+}
+
+/// The packages that are built from the APKBUILD of a rust version.
+const RUST_PACKAGES: &[&str] = &["cargo", "clippy", "rust", "rustfmt"];
+
+/// Return the rust versions that exceed the retention policy, oldest first. Channels like stable
+/// are never pruned. Returns an error if a remaining package bootstraps from one of the versions.
+pub fn expired_versions(config: &Config) -> anyhow::Result<Vec<&str>> {
+	let keep = match config.retention.rust {
+		Some(keep) => keep,
+		None => return Ok(Vec::new())
+	};
+
+	let versions = config
+		.rust
+		.keys()
+		.filter_map(|channel| {
+			let minor: u32 = VERSION_REGEX.captures(channel)?["minor"].parse().ok()?;
+			Some((minor, channel.as_str()))
+		})
+		.sorted()
+		.map(|(_, version)| version)
+		.collect::<Vec<_>>();
+	let expired = &versions[..versions.len().saturating_sub(keep)];
+
+	for (channel, rust) in config.rust.iter().sorted_by_key(|(channel, _)| channel.as_str()) {
+		if !rust.bootsys && !expired.contains(&channel.as_str()) && expired.contains(&rust.bootver.as_str()) {
+			return Err(anyhow!(
+				"Refusing to prune Rust {} because Rust {} bootstraps from it",
+				rust.bootver,
+				channel
+			))
+			.kind(ErrorKind::Config);
+		}
+	}
+	Ok(expired.to_vec())
+}
+
+/// Return the files of the packages of the rust versions inside the repodir, together with their
+/// keys in the repository.
+async fn expired_files(config: &Config, repodir: &Path, versions: &[&str]) -> anyhow::Result<Vec<(PathBuf, String)>> {
+	let dir = config.alpine.repo_dir();
+	let mut entries = match fs::read_dir(repodir.join(&dir)).await {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(err) => return Err(err).context("Failed to read repository directory")
+	};
+
+	let mut files = Vec::new();
+	while let Some(entry) = entries.next().await {
+		let entry = entry?;
+		let file_name = entry.file_name();
+		let file_name = match file_name.to_str() {
+			Some(file_name) => file_name,
+			None => continue
+		};
+		let expired = versions.iter().any(|version| {
+			RUST_PACKAGES
+				.iter()
+				.any(|pkg| file_name.starts_with(&format!("{}-{}-", pkg, version)))
+		});
+		if expired {
+			files.push((entry.path(), format!("{}/{}", dir, file_name)));
+		}
+	}
+	Ok(files)
+}
+
+/// Remove the rust versions from the config file, delete their packages from the repository of
+/// every Alpine release and architecture and rebuild the index, see [expired_versions].
+pub async fn prune(
+	config_path: &Path,
+	config: &Config,
+	versions: &[&str],
+	repodir: &Path,
+	server: &ServerArgs,
+	dry_run: bool
+) -> anyhow::Result<()> {
+	let mut targets = Vec::new();
+	for (version, arch) in config.alpine.targets() {
+		let config = config.for_alpine(version, arch);
+		let files = expired_files(&config, repodir, versions).await?;
+		targets.push((config, files));
+	}
+
+	if dry_run {
+		println!("The following rust versions would be pruned: {}", versions.join(", "));
+		for (_, key) in targets.iter().flat_map(|(_, files)| files) {
+			println!("  {}", key);
+		}
+		return Ok(());
+	}
+
+	// only remove the packages from the repodir, so that the repository stays untouched until the
+	// new index was built
+	let kind = server.kind()?;
+	let storage = repo::open(&config.deploy.repo).await.kind(ErrorKind::Publish)?;
+	for (path, _) in targets.iter().flat_map(|(_, files)| files) {
+		repo::remove_local(path).await?;
+	}
+
+	let session = Session::start(config, repodir, kind, false).await?;
+	let res = signal::interruptible(async {
+		let targets = targets.iter().filter(|(_, files)| !files.is_empty()).collect::<Vec<_>>();
+		for (config, _) in &targets {
+			info!("Rebuilding the index for {}", config.alpine);
+			session
+				.rebuild_index(config, false)
+				.await
+				.context("Failed to rebuild the index")
+				.kind(ErrorKind::Build)?;
+			session
+				.download_repo_changes(config, repodir)
+				.await
+				.context("Failed to download the index")
+				.kind(ErrorKind::Build)?;
+		}
+
+		// the old index still lists the deleted packages until the new one is uploaded
+		for (path, key) in targets.iter().flat_map(|(_, files)| files) {
+			repo::delete(&*storage, path, key)
+				.await
+				.with_context(|| format!("Failed to delete {}", key))
+				.kind(ErrorKind::Publish)?;
+		}
+		for (config, _) in &targets {
+			session
+				.upload_repo_changes(config, repodir)
+				.await
+				.context("Failed to upload the index")
+				.kind(ErrorKind::Publish)?;
+		}
+		Ok(())
+	})
+	.await;
+	res.and(session.stop().await)?;
+
+	// only remove the versions from the config once their packages are gone, so that a failure
+	// above leaves a config that matches the repository
+	config::remove_rust_versions(config_path, versions).await
+}
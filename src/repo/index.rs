@@ -0,0 +1,138 @@
+use anyhow::Context;
+use flate2::read::MultiGzDecoder;
+use std::{io::Read, path::Path};
+
+/// A package listed in an `APKINDEX.tar.gz`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IndexEntry {
+	pub name: String,
+	pub version: String,
+	/// The name of the APKBUILD that the package was built from.
+	pub origin: String,
+	/// The names of the packages that the package depends on, without version constraints.
+	pub depends: Vec<String>,
+	/// The names that the package provides, without versions.
+	pub provides: Vec<String>
+}
+
+impl IndexEntry {
+	/// Return the name of the .apk file of the package.
+	pub(crate) fn file_name(&self) -> String {
+		format!("{}-{}.apk", self.name, self.version)
+	}
+
+	/// Return whether the package can be installed to satisfy the dependency.
+	pub(crate) fn satisfies(&self, dep: &str) -> bool {
+		self.name == dep || self.provides.iter().any(|name| name == dep)
+	}
+}
+
+/// Split a list of dependencies like `rust-stdlib=1.50.0-r0 so:libc.musl-x86_64.so.1` into their
+/// names, skipping conflicts like `!rust`.
+fn names(list: &str) -> Vec<String> {
+	list.split_whitespace()
+		.filter(|dep| !dep.starts_with('!'))
+		.map(|dep| dep.split(&['=', '<', '>', '~'][..]).next().unwrap_or_default().to_owned())
+		.collect()
+}
+
+/// Read all packages from an `APKINDEX.tar.gz`. The signature and the index are separate gzip
+/// streams, but together they form a single tar archive.
+pub(crate) fn read_index(path: &Path) -> anyhow::Result<Vec<IndexEntry>> {
+	let file = std::fs::File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+	let mut archive = tar::Archive::new(MultiGzDecoder::new(file));
+	let mut index = String::new();
+	for entry in archive.entries().context("Unable to get archive entries")? {
+		let mut entry = entry.context("Unable to get archive entry")?;
+		if entry.path()?.as_os_str() == "APKINDEX" {
+			entry.read_to_string(&mut index).context("Unable to read APKINDEX")?;
+			break;
+		}
+	}
+	Ok(parse_index(&index))
+}
+
+/// Parse the packages from the content of an `APKINDEX` file.
+fn parse_index(index: &str) -> Vec<IndexEntry> {
+	let mut entries = Vec::new();
+	for stanza in index.split("\n\n").filter(|stanza| !stanza.trim().is_empty()) {
+		let mut entry = IndexEntry::default();
+		for line in stanza.lines() {
+			match line.split_at(line.find(':').map(|idx| idx + 1).unwrap_or_default()) {
+				("P:", name) => entry.name = name.to_owned(),
+				("V:", version) => entry.version = version.to_owned(),
+				("o:", origin) => entry.origin = origin.to_owned(),
+				("D:", depends) => entry.depends = names(depends),
+				("p:", provides) => entry.provides = names(provides),
+				_ => {}
+			}
+		}
+		entries.push(entry);
+	}
+	entries
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::{write::GzEncoder, Compression};
+	use std::io::Write;
+
+	const INDEX: &str = "C:Q1abc=\nP:rust\nV:1.50.0-r0\no:rust\nD:rust-stdlib=1.50.0-r0 so:libc.musl-x86_64.so.1\n\n\
+	                     C:Q1def=\nP:rust-stdlib\nV:1.50.0-r0\no:rust\nD:musl>=1.2 !rust-nightly\np:rust-std=1.50.0\n\n";
+
+	#[test]
+	fn dependency_names() {
+		assert_eq!(names("a=1.0 b>=2 c<3 d~4 !e f"), vec!["a", "b", "c", "d", "f"]);
+		assert!(names("").is_empty());
+	}
+
+	#[test]
+	fn parse_stanzas() {
+		let entries = parse_index(INDEX);
+		assert_eq!(entries.len(), 2);
+
+		assert_eq!(entries[0].name, "rust");
+		assert_eq!(entries[0].version, "1.50.0-r0");
+		assert_eq!(entries[0].origin, "rust");
+		assert_eq!(entries[0].depends, vec!["rust-stdlib", "so:libc.musl-x86_64.so.1"]);
+		assert!(entries[0].provides.is_empty());
+		assert_eq!(entries[0].file_name(), "rust-1.50.0-r0.apk");
+
+		assert_eq!(entries[1].depends, vec!["musl"]);
+		assert_eq!(entries[1].provides, vec!["rust-std"]);
+		assert!(entries[1].satisfies("rust-stdlib"));
+		assert!(entries[1].satisfies("rust-std"));
+		assert!(!entries[1].satisfies("rust"));
+	}
+
+	fn tar_gz(files: &[(&str, &str)], finish: bool) -> Vec<u8> {
+		let mut builder = tar::Builder::new(Vec::new());
+		for (name, content) in files {
+			let mut header = tar::Header::new_gnu();
+			header.set_size(content.len() as u64);
+			header.set_mode(0o644);
+			builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+		}
+		let mut tar = builder.into_inner().unwrap();
+		if !finish {
+			// the signature stream has no end-of-archive marker
+			tar.truncate(tar.len() - 1024);
+		}
+		let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+		gz.write_all(&tar).unwrap();
+		gz.finish().unwrap()
+	}
+
+	#[test]
+	fn read_signed_index() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("APKINDEX.tar.gz");
+		let mut file = tar_gz(&[(".SIGN.RSA.key.rsa.pub", "signature")], false);
+		file.extend(tar_gz(&[("DESCRIPTION", "alpine-rust"), ("APKINDEX", INDEX)], true));
+		std::fs::write(&path, file).unwrap();
+
+		let entries = read_index(&path).unwrap();
+		assert_eq!(entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), vec!["rust", "rust-stdlib"]);
+	}
+}
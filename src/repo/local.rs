@@ -0,0 +1,78 @@
+use super::{Object, Storage};
+use anyhow::Context;
+use std::{
+	fs::Metadata,
+	io,
+	path::{Path, PathBuf},
+	time::UNIX_EPOCH
+};
+use tokio::fs;
+
+/// A plain directory on the local machine, e.g. for air-gapped mirrors.
+pub(super) struct LocalStorage {
+	dir: PathBuf
+}
+
+impl LocalStorage {
+	pub(super) fn new(dir: &Path) -> Self {
+		Self { dir: dir.to_owned() }
+	}
+}
+
+/// The etag of a file is made up of its size and modification time.
+fn etag(meta: &Metadata) -> io::Result<String> {
+	let modified = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+	Ok(format!("\"{:x}-{:x}\"", meta.len(), modified.as_nanos()))
+}
+
+fn list_dir(dir: &Path, prefix: &str, objs: &mut Vec<Object>) -> io::Result<()> {
+	for entry in std::fs::read_dir(dir)? {
+		let entry = entry?;
+		let key = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+		let meta = entry.metadata()?;
+		if meta.is_dir() {
+			list_dir(&entry.path(), &format!("{}/", key), objs)?;
+		} else {
+			objs.push(Object { key, etag: etag(&meta)? });
+		}
+	}
+	Ok(())
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+	async fn list(&self) -> anyhow::Result<Vec<Object>> {
+		let mut objs = Vec::new();
+		list_dir(&self.dir, "", &mut objs).with_context(|| format!("Failed to list {}", self.dir.display()))?;
+		Ok(objs)
+	}
+
+	async fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+		fs::copy(self.dir.join(key), path)
+			.await
+			.with_context(|| format!("Failed to copy {} from {}", key, self.dir.display()))?;
+		Ok(())
+	}
+
+	async fn put(&self, path: &Path, key: &str) -> anyhow::Result<String> {
+		let dest = self.dir.join(key);
+		if let Some(parent) = dest.parent() {
+			fs::create_dir_all(parent)
+				.await
+				.with_context(|| format!("Failed to create {}", parent.display()))?;
+		}
+		fs::copy(path, &dest)
+			.await
+			.with_context(|| format!("Failed to copy {} to {}", key, self.dir.display()))?;
+		let meta = fs::metadata(&dest).await?;
+		Ok(etag(&meta)?)
+	}
+
+	async fn delete(&self, key: &str) -> anyhow::Result<()> {
+		match fs::remove_file(self.dir.join(key)).await {
+			Ok(()) => Ok(()),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+			Err(err) => Err(err).with_context(|| format!("Failed to remove {} from {}", key, self.dir.display()))
+		}
+	}
+}
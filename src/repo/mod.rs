@@ -0,0 +1,320 @@
+use crate::{
+	config::{self, DeployRepo, StorageKind},
+	error::{ErrorKind, ErrorKindExt},
+	Config
+};
+use anyhow::{anyhow, Context};
+use futures_util::StreamExt;
+use std::{
+	collections::BTreeSet,
+	ffi::{OsStr, OsString},
+	path::{Path, PathBuf}
+};
+use tokio::{
+	fs::{self, File},
+	io::{self, AsyncReadExt, AsyncWriteExt}
+};
+
+mod index;
+mod local;
+mod s3;
+mod sftp;
+
+pub(super) use self::index::read_index;
+use self::{local::LocalStorage, s3::S3Storage, sftp::SftpStorage};
+
+/// The name of the copy of the remote index inside the repodir, see [download_index].
+pub(super) const REMOTE_INDEX: &str = ".APKINDEX.remote.tar.gz";
+
+/// A file stored in the repository.
+pub(super) struct Object {
+	/// The path of the file relative to the repository root.
+	pub key: String,
+	/// A value that changes whenever the content of the file changes.
+	pub etag: String
+}
+
+/// The storage backend that holds the repository.
+#[async_trait]
+pub(super) trait Storage: Send + Sync {
+	/// List all files in the repository.
+	async fn list(&self) -> anyhow::Result<Vec<Object>>;
+
+	/// Download the file with the key to the path.
+	async fn get(&self, key: &str, path: &Path) -> anyhow::Result<()>;
+
+	/// Upload the file at the path to the key and return its new etag.
+	async fn put(&self, path: &Path, key: &str) -> anyhow::Result<String>;
+
+	/// Delete the file with the key.
+	async fn delete(&self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Open the storage backend selected in the config.
+pub(super) async fn open(repo: &DeployRepo) -> anyhow::Result<Box<dyn Storage>> {
+	Ok(match repo.storage {
+		StorageKind::S3 => Box::new(S3Storage::new(repo)),
+		StorageKind::Local => {
+			let local = repo
+				.local
+				.as_ref()
+				.ok_or_else(|| anyhow!("deploy.repo.local must be set when using local storage"))
+				.kind(ErrorKind::Config)?;
+			Box::new(LocalStorage::new(&local.dir))
+		},
+		StorageKind::Sftp => {
+			let sftp = repo
+				.sftp
+				.as_ref()
+				.ok_or_else(|| anyhow!("deploy.repo.sftp must be set when using sftp storage"))
+				.kind(ErrorKind::Config)?;
+			Box::new(SftpStorage::connect(sftp).await.context("Failed to connect to the SFTP server")?)
+		}
+	})
+}
+
+fn etag_path(path: &Path, file_name: &OsStr) -> anyhow::Result<PathBuf> {
+	let parent = path.parent().ok_or(anyhow!("{} does not have a parent", path.display()))?;
+	let mut etag_name = OsString::from(".");
+	etag_name.push(file_name);
+	etag_name.push(".etag");
+	Ok(parent.join(&etag_name))
+}
+
+pub(super) async fn list(storage: &dyn Storage) -> anyhow::Result<BTreeSet<String>> {
+	info!("Listing repository content");
+	let list = storage.list().await?;
+	Ok(list.into_iter().map(|obj| obj.key).collect())
+}
+
+/// Download an object into the dest directory, unless the etag file shows that it is already
+/// present. Returns whether the object was downloaded.
+async fn download_object(storage: &dyn Storage, dest: &Path, obj: &Object) -> anyhow::Result<bool> {
+	let key = &obj.key;
+	let path = dest.join(key);
+	let etag_path = etag_path(&path, path.file_name().ok_or(anyhow!("Key does not have a filename"))?)?;
+
+	let etag = match File::open(&etag_path).await {
+		Ok(mut file) => {
+			let mut etag = String::new();
+			file.read_to_string(&mut etag).await.context("Failed to read etag file")?;
+			Some(etag)
+		},
+		Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+		Err(err) => return Err(err).context("Failed to read etag file")
+	};
+	if etag.as_deref() == Some(&obj.etag) {
+		return Ok(false);
+	}
+
+	info!("Downloading {} to {}", key, path.display());
+	if let Some(parent) = path.parent() {
+		fs::create_dir_all(parent)
+			.await
+			.context("Failed to create destination path")?;
+	}
+
+	storage.get(key, &path).await?;
+
+	let mut etag_file = match File::create(etag_path).await {
+		Ok(file) => file,
+		Err(err) => {
+			error!("Failed to create etag file: {}", err);
+			return Ok(true);
+		}
+	};
+	if let Err(err) = etag_file.write_all(obj.etag.as_bytes()).await {
+		error!("Failed to write etag file: {}", err);
+	}
+	Ok(true)
+}
+
+/// Download the whole repository.
+pub(super) async fn download(storage: &dyn Storage, dest: &Path) -> anyhow::Result<()> {
+	info!("Synchronizing repository to {}", dest.display());
+	let objs = storage.list().await?;
+
+	for obj in &objs {
+		download_object(storage, dest, obj).await?;
+	}
+
+	info!("Synchronization finished");
+	Ok(())
+}
+
+/// Download only the index and the APKBUILD hashes of every Alpine release and architecture,
+/// which is all that is needed to determine the outdated packages. The index is also kept as
+/// [REMOTE_INDEX], so that packages that were never downloaded are not dropped from the index
+/// when it is rebuilt after a build, see [build::rebuild_index](crate::build::rebuild_index).
+pub(super) async fn download_index(storage: &dyn Storage, config: &Config, dest: &Path) -> anyhow::Result<()> {
+	info!("Synchronizing repository index to {}", dest.display());
+	let objs = storage.list().await?;
+
+	for (version, arch) in config.alpine.targets() {
+		let dir = config::repo_dir(version, arch);
+		let index_key = format!("{}/APKINDEX.tar.gz", dir);
+		let mut downloaded = false;
+		for obj in &objs {
+			if obj.key == index_key {
+				downloaded = download_object(storage, dest, obj).await?;
+			} else if obj.key.starts_with(&format!("{}/", dir)) && obj.key.ends_with(".APKBUILD.sha512") {
+				download_object(storage, dest, obj).await?;
+			}
+		}
+
+		let index = dest.join(&index_key);
+		let remote_index = dest.join(&dir).join(REMOTE_INDEX);
+		if downloaded || (index.exists() && !remote_index.exists()) {
+			fs::copy(&index, &remote_index)
+				.await
+				.with_context(|| format!("Failed to copy {}", index.display()))?;
+		}
+	}
+
+	info!("Synchronization finished");
+	Ok(())
+}
+
+/// Download the packages of the apk keys together with all other packages that were built from
+/// the same APKBUILD and all packages that they depend on at runtime, e.g. to bootstrap a build.
+/// The packages are looked up in the index of the repodir, see [download_index].
+pub(super) async fn download_packages(
+	storage: &dyn Storage,
+	config: &Config,
+	dest: &Path,
+	apk_keys: &[String]
+) -> anyhow::Result<()> {
+	let dir = config.alpine.repo_dir();
+	let index_path = dest.join(&dir).join("APKINDEX.tar.gz");
+	if apk_keys.is_empty() || !index_path.exists() {
+		return Ok(());
+	}
+	let index = read_index(&index_path)?;
+
+	let mut queue = Vec::new();
+	for apk_key in apk_keys {
+		let entry = index
+			.iter()
+			.find(|entry| apk_key == &format!("{}/{}", dir, entry.file_name()))
+			.ok_or_else(|| anyhow!("{} is not listed in the repository index", apk_key))?;
+		queue.extend(
+			index
+				.iter()
+				.filter(|other| other.origin == entry.origin && other.version == entry.version)
+		);
+	}
+
+	// dependencies on packages that are not in our index are installed from the alpine repositories
+	let mut keys = BTreeSet::new();
+	while let Some(entry) = queue.pop() {
+		if !keys.insert(format!("{}/{}", dir, entry.file_name())) {
+			continue;
+		}
+		queue.extend(
+			index
+				.iter()
+				.filter(|other| entry.depends.iter().any(|dep| other.satisfies(dep)))
+		);
+	}
+
+	let objs = storage.list().await?;
+	for obj in objs.iter().filter(|obj| keys.contains(&obj.key)) {
+		download_object(storage, dest, obj).await?;
+	}
+	Ok(())
+}
+
+/// Return whether the repodir contains a copy of the remote index, i.e. the repository was only
+/// partially downloaded.
+pub(super) fn has_remote_index(config: &Config, repodir: &Path) -> bool {
+	repodir.join(config.alpine.repo_dir()).join(REMOTE_INDEX).exists()
+}
+
+pub(super) async fn upload(storage: &dyn Storage, path: impl AsRef<Path>, key: &str) -> anyhow::Result<()> {
+	let path = path.as_ref();
+	let file_name = path
+		.file_name()
+		.ok_or(anyhow!("{} does not have a filename", path.display()))?;
+
+	info!("Uploading {} from {}", key, path.display());
+	let etag = storage.put(path, key).await?;
+
+	let mut etag_file = match File::create(etag_path(path, file_name)?).await {
+		Ok(file) => file,
+		Err(err) => {
+			error!("Failed to create etag file: {}", err);
+			return Ok(());
+		}
+	};
+	if let Err(err) = etag_file.write_all(etag.as_bytes()).await {
+		error!("Failed to write etag file: {}", err);
+	}
+
+	Ok(())
+}
+
+/// Delete a file from the repository and from the repodir.
+pub(super) async fn delete(storage: &dyn Storage, path: &Path, key: &str) -> anyhow::Result<()> {
+	info!("Deleting {}", key);
+	storage.delete(key).await?;
+	remove_local(path).await
+}
+
+/// Remove a file and its etag file from the repodir, but not from the repository.
+pub(super) async fn remove_local(path: &Path) -> anyhow::Result<()> {
+	let file_name = path
+		.file_name()
+		.ok_or(anyhow!("{} does not have a filename", path.display()))?;
+	for path in &[path.to_owned(), etag_path(path, file_name)?] {
+		match fs::remove_file(path).await {
+			Ok(()) => {},
+			Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+			Err(err) => return Err(err).with_context(|| format!("Unable to remove {}", path.display()))
+		}
+	}
+	Ok(())
+}
+
+/// Upload all packages that were changed since they were last downloaded or uploaded. A file
+/// counts as changed if its etag file is missing or older than the file itself.
+pub(super) async fn upload_changes(config: &Config, repodir: &Path) -> anyhow::Result<()> {
+	let storage = open(&config.deploy.repo).await?;
+	let dir = config.alpine.repo_dir();
+	let mut entries = fs::read_dir(repodir.join(&dir))
+		.await
+		.context("Failed to read repository directory")?;
+
+	let mut res: anyhow::Result<()> = Ok(());
+	let mut uploaded = 0;
+	while let Some(entry) = entries.next().await {
+		let entry = entry?;
+		let file_name = entry.file_name();
+		match file_name.to_str() {
+			Some(name) if !name.starts_with('.') => {},
+			_ => continue
+		}
+
+		let path = entry.path();
+		let modified = entry.metadata().await?.modified()?;
+		let uploaded_at = match fs::metadata(etag_path(&path, &file_name)?).await {
+			Ok(meta) => Some(meta.modified()?),
+			Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+			Err(err) => return Err(err).context("Failed to read etag file")
+		};
+		if matches!(uploaded_at, Some(uploaded_at) if uploaded_at >= modified) {
+			continue;
+		}
+
+		let key = format!("{}/{}", dir, file_name.to_string_lossy());
+		if let Err(err) = upload(&*storage, &path, &key).await {
+			error!("Error uploading {}: {}", path.display(), err);
+			res = Err(err);
+		}
+		uploaded += 1;
+	}
+
+	if uploaded == 0 {
+		info!("No changes to commit");
+	}
+	res
+}
@@ -0,0 +1,92 @@
+use super::{Object, Storage};
+use crate::config::DeployRepo;
+use anyhow::{anyhow, Context};
+use s3::{creds::Credentials, Bucket, Region};
+use std::path::Path;
+use tokio::{fs::File, io::AsyncReadExt};
+
+/// An S3-compatible bucket, e.g. on a MinIO server.
+pub(super) struct S3Storage {
+	repo: DeployRepo
+}
+
+impl S3Storage {
+	pub(super) fn new(repo: &DeployRepo) -> Self {
+		Self { repo: repo.clone() }
+	}
+
+	fn region(&self) -> Region {
+		Region::Custom {
+			region: self.repo.region.clone(),
+			endpoint: self.repo.endpoint.clone()
+		}
+	}
+
+	fn public_bucket(&self) -> anyhow::Result<Bucket> {
+		Bucket::new_public_with_path_style(&self.repo.bucket, self.region()).context("Failed to open bucket")
+	}
+
+	fn bucket(&self) -> anyhow::Result<Bucket> {
+		let access_key = self.repo.access_key.get()?;
+		let secret_key = self.repo.secret_key.get()?;
+		let creds =
+			Credentials::new(Some(&access_key), Some(&secret_key), None, None, None).context("Failed to get MinIO creds")?;
+		Bucket::new_with_path_style(&self.repo.bucket, self.region(), creds).context("Failed to open bucket")
+	}
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+	async fn list(&self) -> anyhow::Result<Vec<Object>> {
+		let bucket = self.public_bucket()?;
+		let list = bucket.list("/".to_owned(), None).await.context("Failed to list bucket")?;
+		Ok(list
+			.into_iter()
+			.flat_map(|res| res.contents.into_iter())
+			.map(|obj| Object {
+				key: obj.key.trim_start_matches('/').to_owned(),
+				etag: obj.e_tag
+			})
+			.collect())
+	}
+
+	async fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+		let bucket = self.public_bucket()?;
+		let mut file = std::fs::File::create(path).context("Failed to create destination file")?;
+		bucket
+			.get_object_stream(key, &mut file)
+			.await
+			.context("Failed to download from bucket")?;
+		Ok(())
+	}
+
+	async fn put(&self, path: &Path, key: &str) -> anyhow::Result<String> {
+		let bucket = self.bucket()?;
+		bucket
+			.put_object_stream(path, key)
+			.await
+			.context("Failed to upload to bucket")?;
+
+		// the etag of a bucket object is the quoted md5 sum of its content
+		let mut file = File::open(path).await?;
+		let mut hash = md5::Context::new();
+		let mut buf = [0u8; 8192];
+		loop {
+			let len = file.read(&mut buf).await?;
+			if len == 0 {
+				break;
+			}
+			hash.consume(&buf[..len]);
+		}
+		Ok(format!("\"{:x}\"", hash.compute()))
+	}
+
+	async fn delete(&self, key: &str) -> anyhow::Result<()> {
+		let bucket = self.bucket()?;
+		let (_, status) = bucket.delete_object(key).await.context("Failed to delete from bucket")?;
+		if !(200..300).contains(&status) {
+			return Err(anyhow!("Deleting {} returned status {}", key, status));
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,153 @@
+use super::{Object, Storage};
+use crate::config::DeploySftp;
+use anyhow::{anyhow, Context};
+use ssh2::{ErrorCode, FileStat, Session, Sftp};
+use std::{
+	io,
+	net::{TcpStream, ToSocketAddrs},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::Duration
+};
+use tokio::task::spawn_blocking;
+
+/// The `LIBSSH2_FX_NO_SUCH_FILE` error code.
+const NO_SUCH_FILE: i32 = 2;
+
+/// How long to wait for the server before giving up on a connection attempt or an operation.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A directory on a remote server that is accessed via SFTP. All operations are blocking, so they
+/// are run with [spawn_blocking].
+pub(super) struct SftpStorage {
+	sftp: Arc<Mutex<Sftp>>,
+	dir: PathBuf
+}
+
+impl SftpStorage {
+	pub(super) async fn connect(account: &DeploySftp) -> anyhow::Result<Self> {
+		info!("Connecting to {}:{}", account.host, account.port);
+		let account = account.clone();
+		spawn_blocking(move || {
+			let addr = (account.host.as_str(), account.port)
+				.to_socket_addrs()?
+				.next()
+				.ok_or_else(|| anyhow!("Unable to resolve {}", account.host))?;
+			let tcp = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+			tcp.set_read_timeout(Some(TIMEOUT))?;
+			tcp.set_write_timeout(Some(TIMEOUT))?;
+			let mut sess = Session::new()?;
+			sess.set_timeout(TIMEOUT.as_millis() as u32);
+			sess.set_tcp_stream(tcp);
+			sess.handshake()?;
+			match &account.password {
+				Some(password) => sess.userauth_password(&account.username, &password.get()?)?,
+				None => sess.userauth_agent(&account.username)?
+			};
+			let sftp = sess.sftp().context("Failed to start SFTP session")?;
+			Ok(Self {
+				sftp: Arc::new(Mutex::new(sftp)),
+				dir: account.dir
+			})
+		})
+		.await?
+	}
+
+	/// Run a blocking operation on the SFTP session and the remote directory.
+	async fn run<F, T>(&self, f: F) -> anyhow::Result<T>
+	where
+		F: FnOnce(&Sftp, &Path) -> anyhow::Result<T> + Send + 'static,
+		T: Send + 'static
+	{
+		let sftp = Arc::clone(&self.sftp);
+		let dir = self.dir.clone();
+		spawn_blocking(move || f(&sftp.lock().unwrap(), &dir)).await?
+	}
+}
+
+fn list_dir(sftp: &Sftp, dir: &Path, prefix: &str, objs: &mut Vec<Object>) -> anyhow::Result<()> {
+	for (path, stat) in sftp.readdir(dir)? {
+		let name = match path.file_name() {
+			Some(name) => name.to_string_lossy(),
+			None => continue
+		};
+		let key = format!("{}{}", prefix, name);
+		if stat.is_dir() {
+			list_dir(sftp, &path, &format!("{}/", key), objs)?;
+		} else {
+			objs.push(Object { key, etag: etag(&stat) });
+		}
+	}
+	Ok(())
+}
+
+fn create_dir_all(sftp: &Sftp, dir: &Path) -> anyhow::Result<()> {
+	if sftp.stat(dir).is_ok() {
+		return Ok(());
+	}
+	if let Some(parent) = dir.parent() {
+		create_dir_all(sftp, parent)?;
+	}
+	sftp.mkdir(dir, 0o755)?;
+	Ok(())
+}
+
+/// The etag of a file is made up of its size and modification time.
+fn etag(stat: &FileStat) -> String {
+	format!("\"{:x}-{:x}\"", stat.size.unwrap_or_default(), stat.mtime.unwrap_or_default())
+}
+
+#[async_trait]
+impl Storage for SftpStorage {
+	async fn list(&self) -> anyhow::Result<Vec<Object>> {
+		self.run(|sftp, dir| {
+			let mut objs = Vec::new();
+			list_dir(sftp, dir, "", &mut objs).with_context(|| format!("Failed to list {}", dir.display()))?;
+			Ok(objs)
+		})
+		.await
+	}
+
+	async fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+		let key = key.to_owned();
+		let path = path.to_owned();
+		self.run(move |sftp, dir| {
+			let mut remote = sftp
+				.open(&dir.join(&key))
+				.with_context(|| format!("Failed to open {}", key))?;
+			let mut file = std::fs::File::create(path).context("Failed to create destination file")?;
+			io::copy(&mut remote, &mut file).with_context(|| format!("Failed to download {}", key))?;
+			Ok(())
+		})
+		.await
+	}
+
+	async fn put(&self, path: &Path, key: &str) -> anyhow::Result<String> {
+		let key = key.to_owned();
+		let path = path.to_owned();
+		self.run(move |sftp, dir| {
+			let dest = dir.join(&key);
+			if let Some(parent) = dest.parent() {
+				create_dir_all(sftp, parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+			}
+			let mut file = std::fs::File::open(path)?;
+			let mut remote = sftp.create(&dest).with_context(|| format!("Failed to create {}", key))?;
+			io::copy(&mut file, &mut remote).with_context(|| format!("Failed to upload {}", key))?;
+			drop(remote);
+
+			let stat = sftp.stat(&dest)?;
+			Ok(etag(&stat))
+		})
+		.await
+	}
+
+	async fn delete(&self, key: &str) -> anyhow::Result<()> {
+		let key = key.to_owned();
+		self.run(move |sftp, dir| match sftp.unlink(&dir.join(&key)) {
+			Ok(()) => Ok(()),
+			Err(err) if err.code() == ErrorCode::SFTP(NO_SUCH_FILE) => Ok(()),
+			Err(err) => Err(err).with_context(|| format!("Failed to delete {}", key))
+		})
+		.await
+	}
+}
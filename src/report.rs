@@ -0,0 +1,275 @@
+use crate::{config::Alpine, docker::ContainerExit, error::ErrorKindExt, state::Phase};
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+	fmt::{self, Write},
+	future::Future,
+	path::{Path, PathBuf},
+	sync::Mutex,
+	time::{Duration, Instant}
+};
+use tokio::fs;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+	Success,
+	Failure,
+	Skipped,
+	NotRun
+}
+
+/// The result of a single test run by `build::rust::test_package`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TestResult {
+	pub name: String,
+	pub duration_secs: f64,
+	pub status: Status,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub exit_code: Option<i64>
+}
+
+impl TestResult {
+	pub fn new(name: String, duration: Duration, res: anyhow::Result<()>) -> Self {
+		let (status, error, exit_code) = match res {
+			Ok(()) => (Status::Success, None, None),
+			Err(err) => (Status::Failure, Some(format!("{:#}", err)), ContainerExit::exit_code(&err))
+		};
+		Self {
+			name,
+			duration_secs: duration.as_secs_f64(),
+			status,
+			error,
+			exit_code
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+struct PhaseReport {
+	phase: Phase,
+	duration_secs: f64,
+	status: Status,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	exit_code: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	reason: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct PackageReport {
+	name: String,
+	alpine: String,
+	arch: String,
+	phases: Vec<PhaseReport>,
+	tests: Vec<TestResult>
+}
+
+/// Collects the outcome of every phase of every package during a run, so that it can be written
+/// as JSON or JUnit XML at the end of the run.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+	packages: Mutex<Vec<PackageReport>>
+}
+
+impl Report {
+	/// Return the default path of the JSON report that belongs to the repodir.
+	pub fn path(repodir: &Path) -> Option<PathBuf> {
+		let mut file_name = repodir.file_name()?.to_owned();
+		file_name.push(".report.json");
+		Some(repodir.with_file_name(file_name))
+	}
+
+	/// Return whether no package was recorded.
+	pub fn is_empty(&self) -> bool {
+		self.packages.lock().unwrap().is_empty()
+	}
+
+	fn with_package<F: FnOnce(&mut PackageReport)>(&self, alpine: &Alpine, name: &str, f: F) {
+		let mut packages = self.packages.lock().unwrap();
+		let idx = match packages
+			.iter()
+			.position(|pkg| pkg.alpine == alpine.version && pkg.arch == alpine.arch && pkg.name == name)
+		{
+			Some(idx) => idx,
+			None => {
+				packages.push(PackageReport {
+					name: name.to_owned(),
+					alpine: alpine.version.clone(),
+					arch: alpine.arch.clone(),
+					phases: Vec::new(),
+					tests: Vec::new()
+				});
+				packages.len() - 1
+			}
+		};
+		f(&mut packages[idx]);
+	}
+
+	/// Run a phase of a package for an Alpine release and architecture and record its outcome.
+	/// Errors are categorized by the phase.
+	pub async fn phase<Fut, T>(&self, alpine: &Alpine, name: &str, phase: Phase, fut: Fut) -> anyhow::Result<T>
+	where
+		Fut: Future<Output = anyhow::Result<T>>
+	{
+		let start = Instant::now();
+		let res = fut.await.kind(phase.error_kind());
+		let (status, error, exit_code) = match &res {
+			Ok(_) => (Status::Success, None, None),
+			Err(err) => (Status::Failure, Some(format!("{:#}", err)), ContainerExit::exit_code(err))
+		};
+		self.with_package(alpine, name, |pkg| {
+			pkg.phases.push(PhaseReport {
+				phase,
+				duration_secs: start.elapsed().as_secs_f64(),
+				status,
+				error,
+				exit_code,
+				reason: None
+			})
+		});
+		res
+	}
+
+	/// Record that a phase of a package was skipped or not run, and why.
+	pub fn skip(&self, alpine: &Alpine, name: &str, phase: Phase, status: Status, reason: &str) {
+		self.with_package(alpine, name, |pkg| {
+			pkg.phases.push(PhaseReport {
+				phase,
+				duration_secs: 0.0,
+				status,
+				error: None,
+				exit_code: None,
+				reason: Some(reason.to_owned())
+			})
+		});
+	}
+
+	/// Record the results of the tests of a package.
+	pub fn tests(&self, alpine: &Alpine, name: &str, tests: &[TestResult]) {
+		self.with_package(alpine, name, |pkg| pkg.tests.extend_from_slice(tests));
+	}
+
+	pub async fn write_json(&self, path: &Path) -> anyhow::Result<()> {
+		let json = serde_json::to_vec_pretty(self)?;
+		fs::write(path, json)
+			.await
+			.with_context(|| format!("Unable to write report to {}", path.display()))?;
+		info!("Wrote report to {}", path.display());
+		Ok(())
+	}
+
+	pub async fn write_junit(&self, path: &Path) -> anyhow::Result<()> {
+		let xml = self.junit()?;
+		fs::write(path, xml)
+			.await
+			.with_context(|| format!("Unable to write JUnit report to {}", path.display()))?;
+		info!("Wrote JUnit report to {}", path.display());
+		Ok(())
+	}
+
+	/// Render the report as JUnit XML. Every package of every Alpine release and architecture is a
+	/// test suite, and every phase and every test of the package is a test case.
+	fn junit(&self) -> Result<String, fmt::Error> {
+		struct TestCase<'a> {
+			classname: String,
+			name: String,
+			time: f64,
+			status: Status,
+			message: Option<&'a str>
+		}
+
+		let packages = self.packages.lock().unwrap();
+		let mut xml = String::new();
+		writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+		writeln!(xml, r#"<testsuites name="alpine-rust">"#)?;
+		for pkg in packages.iter() {
+			let suite = format!("alpine{}/{}/{}", pkg.alpine, pkg.arch, pkg.name);
+			let phases = pkg.phases.iter().map(|phase| TestCase {
+				classname: suite.clone(),
+				name: phase.phase.to_string(),
+				time: phase.duration_secs,
+				status: phase.status,
+				message: phase.error.as_deref().or(phase.reason.as_deref())
+			});
+			let tests = pkg.tests.iter().map(|test| TestCase {
+				classname: format!("{}.tests", suite),
+				name: test.name.clone(),
+				time: test.duration_secs,
+				status: test.status,
+				message: test.error.as_deref()
+			});
+			let cases = phases.chain(tests).collect::<Vec<_>>();
+
+			let failures = cases.iter().filter(|case| case.status == Status::Failure).count();
+			let skipped = cases
+				.iter()
+				.filter(|case| matches!(case.status, Status::Skipped | Status::NotRun))
+				.count();
+			let time: f64 = cases.iter().map(|case| case.time).sum();
+			writeln!(
+				xml,
+				r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+				escape(&suite),
+				cases.len(),
+				failures,
+				skipped,
+				time
+			)?;
+			for case in cases {
+				write!(
+					xml,
+					r#"    <testcase classname="{}" name="{}" time="{:.3}""#,
+					escape(&case.classname),
+					escape(&case.name),
+					case.time
+				)?;
+				match case.status {
+					Status::Success => writeln!(xml, "/>")?,
+					Status::Failure => {
+						let error = case.message.unwrap_or_default();
+						writeln!(xml, ">")?;
+						writeln!(
+							xml,
+							r#"      <failure message="{}">{}</failure>"#,
+							escape(error.lines().next().unwrap_or_default()),
+							escape(error)
+						)?;
+						writeln!(xml, "    </testcase>")?;
+					},
+					Status::Skipped | Status::NotRun => {
+						writeln!(xml, ">")?;
+						writeln!(
+							xml,
+							r#"      <skipped message="{}"/>"#,
+							escape(case.message.unwrap_or_default())
+						)?;
+						writeln!(xml, "    </testcase>")?;
+					}
+				}
+			}
+			writeln!(xml, "  </testsuite>")?;
+		}
+		writeln!(xml, "</testsuites>")?;
+		Ok(xml)
+	}
+}
+
+fn escape(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&apos;"),
+			c => escaped.push(c)
+		}
+	}
+	escaped
+}
@@ -1,4 +1,4 @@
-use crate::{docker::IPv6CIDR, Config};
+use crate::{docker::IPv6CIDR, repo, Config};
 use bollard::Docker;
 use either::Either;
 use std::path::Path;
@@ -9,7 +9,7 @@ pub mod upcloud;
 #[async_trait]
 pub trait Server {
 	/// Install any missing dependencies/keys/... on the server.
-	async fn install(&self, config: &Config, repodir: &Path) -> anyhow::Result<()>;
+	async fn install(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()>;
 
 	/// Connect to the docker daemon running on the server.
 	fn connect_to_docker(&self) -> Result<Docker, bollard::errors::Error>;
@@ -23,8 +23,14 @@ pub trait Server {
 	/// Get the IPv6 CIDR of the docker daemon.
 	fn cidr_v6(&self) -> IPv6CIDR<String>;
 
+	/// Make sure all changes made to the repository on the server are present in the repodir.
+	async fn download_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()>;
+
 	/// Upload any changes made to the repodir.
-	async fn upload_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()>;
+	async fn upload_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+		self.download_repo_changes(config, repodir).await?;
+		repo::upload_changes(config, repodir).await
+	}
 
 	/// Destroy the server if it was created previously.
 	async fn destroy(self) -> anyhow::Result<()>;
@@ -36,8 +42,8 @@ where
 	A: Server + Send + Sync,
 	B: Server + Send + Sync
 {
-	async fn install(&self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
-		self.as_ref()
+	async fn install(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+		self.as_mut()
 			.either(|a| a.install(config, repodir), |b| b.install(config, repodir))
 			.await
 	}
@@ -58,11 +64,11 @@ where
 		self.as_ref().either(A::cidr_v6, B::cidr_v6)
 	}
 
-	async fn upload_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+	async fn download_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
 		self.as_mut()
 			.either(
-				|a| a.upload_repo_changes(config, repodir),
-				|b| b.upload_repo_changes(config, repodir)
+				|a| a.download_repo_changes(config, repodir),
+				|b| b.download_repo_changes(config, repodir)
 			)
 			.await
 	}
@@ -1,7 +1,7 @@
 use super::{UPCLOUD_CORES, UPCLOUD_MEMORY, UPCLOUD_STORAGE};
-use crate::CLIENT;
+use crate::{config::DeployUpcloud, CLIENT};
 use serde::{Deserialize, Serialize};
-use std::{env, time::Duration};
+use std::time::Duration;
 use tokio::time::delay_for;
 
 #[derive(Serialize)]
@@ -236,11 +236,11 @@ impl DeleteServerRequest {
 	}
 }
 
-pub async fn destroy_server(uuid: &str) -> anyhow::Result<()> {
+pub async fn destroy_server(account: &DeployUpcloud, uuid: &str) -> anyhow::Result<()> {
 	info!("Removing Server {}", uuid);
 
-	let username = "msrd0";
-	let password = env::var("UPCLOUD_PASSWORD")?;
+	let username = &account.username;
+	let password = account.password.get()?;
 
 	StopServerRequest::new().send(username, &password, uuid).await?;
 	delay_for(Duration::new(30, 0)).await;
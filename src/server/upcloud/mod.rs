@@ -1,13 +1,14 @@
 use super::Server;
 use crate::{
+	config::{repo_dir, DeployUpcloud},
 	docker::{gen_docker_keys, DockerKeys, IPv6CIDR},
-	repo, Config
+	Config
 };
 use bollard::{Docker, API_DEFAULT_VERSION};
 use futures_util::StreamExt;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde_json::json;
-use std::{collections::HashMap, env, path::Path};
+use std::{collections::HashMap, path::Path};
 use tokio::fs;
 
 mod api;
@@ -45,21 +46,20 @@ pub struct UpcloudServer {
 	password: String,
 	uuid: String,
 	keys: DockerKeys,
-	repo_dir: String,
-	repo_index: HashMap<String, String>
+	account: DeployUpcloud,
+	/// The index of the repository of every Alpine release and architecture, by directory.
+	repo_index: HashMap<String, HashMap<String, String>>
 }
 
 impl UpcloudServer {
-	pub async fn create(config: &Config) -> anyhow::Result<Self> {
+	pub async fn create(account: &DeployUpcloud) -> anyhow::Result<Self> {
 		let rng = thread_rng();
 		let hostname = rng.sample_iter(Alphanumeric).take(10).map(char::from).collect::<String>();
 		let title = format!("alpine-rust-{}", hostname);
 
 		info!("Creating Server {}", title);
-		let username = "msrd0";
-		let password = env::var("UPCLOUD_PASSWORD")?;
 		let req = CreateServerRequest::new(&title, "alpinerust");
-		let res = req.send(username, &password).await?;
+		let res = req.send(&account.username, &account.password.get()?).await?;
 
 		let ip = res.ip_addr().ok_or(anyhow::Error::msg("Server does not have an IP"))?;
 		let password = res.password();
@@ -69,16 +69,23 @@ impl UpcloudServer {
 		let domain = format!("{}.v6.de-fra1.upcloud.host", ip_last_parts(ip));
 
 		// generate some keys for docker to use with TLS
-		let keys = gen_docker_keys(ip, &domain).await?;
+		let keys = match gen_docker_keys(ip, &domain).await {
+			Ok(keys) => keys,
+			Err(err) => {
+				if let Err(err) = destroy_server(account, uuid).await {
+					error!("Failed to destroy the server: {}", err);
+				}
+				return Err(err);
+			}
+		};
 
-		let repo_dir = format!("/var/lib/alpine-rust/{}/alpine-rust/x86_64", config.alpine.version);
 		Ok(UpcloudServer {
 			ip: ip.to_owned(),
 			domain,
 			password: password.to_owned(),
 			uuid: uuid.to_owned(),
 			keys,
-			repo_dir,
+			account: account.clone(),
 			repo_index: HashMap::new()
 		})
 	}
@@ -86,7 +93,7 @@ impl UpcloudServer {
 
 #[async_trait]
 impl Server for UpcloudServer {
-	async fn install(&self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+	async fn install(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
 		// open an SSH connection
 		let mut sess = connect(&self.domain, &self.password).await?;
 
@@ -127,25 +134,35 @@ impl Server for UpcloudServer {
 		run(&mut sess, "systemctl daemon-reload")?;
 		run(&mut sess, "systemctl enable --now docker-tlsverify")?;
 
-		// upload the repository content
-		let dir = format!("/var/lib/alpine-rust/{}/alpine-rust/x86_64", config.alpine.version);
-		run(&mut sess, &format!("mkdir -p {}", dir))?;
-		let mut entries = fs::read_dir(repodir.join(format!("{}/alpine-rust/x86_64", config.alpine.version))).await?;
-		while let Some(entry) = entries.next().await {
-			let entry = entry?;
-			upload(
-				&mut sess,
-				&format!("{}/{}", dir, entry.file_name().to_string_lossy()),
-				&entry.path()
-			)
-			.await?;
+		// upload the repository content of all alpine releases and architectures
+		for (version, arch) in config.alpine.targets() {
+			let repo_dir = repo_dir(version, arch);
+			let dir = format!("/var/lib/alpine-rust/{}", repo_dir);
+			run(&mut sess, &format!("mkdir -p {}", dir))?;
+			let mut entries = fs::read_dir(repodir.join(&repo_dir)).await?;
+			while let Some(entry) = entries.next().await {
+				let entry = entry?;
+				upload(
+					&mut sess,
+					&format!("{}/{}", dir, entry.file_name().to_string_lossy()),
+					&entry.path()
+				)
+				.await?;
+			}
 		}
 		run(&mut sess, "chmod 777 $(find /var/lib/alpine-rust -type d)")?;
-		run(&mut sess, &format!("test ! -e /var/lib/alpine-rust/{}/alpine-rust/x86_64/APKINDEX.tar.gz || chmod 666 $(find /var/lib/alpine-rust -type f)", config.alpine.version))?;
+		run(
+			&mut sess,
+			"test -z \"$(find /var/lib/alpine-rust -type f)\" || chmod 666 $(find /var/lib/alpine-rust -type f)"
+		)?;
 
 		// index the repository
-		let repo_index = index(&mut sess, &dir)?;
-		debug!("Index: {:?}", repo_index);
+		for (version, arch) in config.alpine.targets() {
+			let repo_dir = repo_dir(version, arch);
+			let index = index(&mut sess, &format!("/var/lib/alpine-rust/{}", repo_dir))?;
+			debug!("Index of Alpine {} ({}): {:?}", version, arch, index);
+			self.repo_index.insert(repo_dir, index);
+		}
 
 		Ok(())
 	}
@@ -175,41 +192,38 @@ impl Server for UpcloudServer {
 		UPCLOUD_IPv6CIDR.to_owned()
 	}
 
-	async fn upload_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+	async fn download_repo_changes(&mut self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
 		// establish a new ssh session
 		let mut sess = connect(&self.domain, &self.password).await?;
 
 		// pull the current index
-		let dir = format!("/var/lib/alpine-rust/{}/alpine-rust/x86_64", config.alpine.version);
+		let repo_dir = config.alpine.repo_dir();
+		let dir = format!("/var/lib/alpine-rust/{}", repo_dir);
 		let new_index = index(&mut sess, &dir)?;
 
 		// get all updated files - the build will never delete files
+		let old_index = self.repo_index.get(&repo_dir);
 		let updated = new_index
-			.into_iter()
-			.filter(|(file, hash)| self.repo_index.get(file.as_str()) != Some(&hash))
+			.iter()
+			.filter(|(file, hash)| old_index.and_then(|index| index.get(file.as_str())) != Some(hash))
 			.map(|(file, _)| file)
 			.collect::<Vec<_>>();
 		if updated.is_empty() {
-			info!("No changes to commit");
+			info!("No changes to download");
 			return Ok(());
 		}
 
-		// download those files and upload to the repo
-		let mut res: anyhow::Result<()> = Ok(());
-		for file in &updated {
-			let path = format!("{}/alpine-rust/x86_64/{}", config.alpine.version, file);
-			let dest = repodir.join(&path);
-			download(&mut sess, &format!("{}/{}", dir, file), &dest).await?;
-			if let Err(err) = repo::upload(&dest, &path).await {
-				error!("Error uploading {}: {}", path, err);
-				res = Err(err);
-			}
+		// download those files into the repodir
+		for file in updated {
+			let path = format!("{}/{}", repo_dir, file);
+			download(&mut sess, &format!("{}/{}", dir, file), &repodir.join(&path)).await?;
 		}
-		res
+		self.repo_index.insert(repo_dir, new_index);
+		Ok(())
 	}
 
 	async fn destroy(self) -> anyhow::Result<()> {
-		destroy_server(&self.uuid).await
+		destroy_server(&self.account, &self.uuid).await
 	}
 }
 
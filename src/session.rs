@@ -0,0 +1,149 @@
+use crate::{
+	build,
+	docker::{self, CaddyContainer, IPv6CIDR},
+	error::{ErrorKind, ErrorKindExt},
+	server::{local::LocalServer, upcloud::UpcloudServer, Server},
+	signal, Config
+};
+use anyhow::Context;
+use bollard::Docker;
+use either::Either;
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tokio::sync::Mutex;
+
+/// The kind of server that runs the docker daemon.
+#[derive(Clone, Copy, Debug)]
+pub enum ServerKind {
+	Local,
+	Upcloud
+}
+
+/// A connection to a docker daemon, together with the server it is running on and, if requested,
+/// the caddy container that serves the repository to the test containers.
+pub struct Session {
+	server: Mutex<Either<LocalServer, UpcloudServer>>,
+	pub docker: Arc<Docker>,
+	pub repomount: String,
+	pub cores: u16,
+	pub cidr_v6: IPv6CIDR<String>,
+	caddy: Option<CaddyContainer>,
+	/// Parallel builds share the repository, so only one index of each repo dir may be rebuilt at a time.
+	index_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+	_guard: signal::Guard
+}
+
+impl Session {
+	/// Create and install the server, connect to its docker daemon and optionally start caddy.
+	/// If anything fails after the server was created, the server is destroyed again.
+	pub async fn start(config: &Config, repodir: &Path, kind: ServerKind, with_caddy: bool) -> anyhow::Result<Self> {
+		// make sure signals don't terminate the process while the server exists
+		let guard = signal::Guard::new();
+
+		// connect to docker - create a server
+		let mut server = match kind {
+			ServerKind::Local => Either::Left(LocalServer),
+			ServerKind::Upcloud => Either::Right(
+				UpcloudServer::create(&config.deploy.upcloud)
+					.await
+					.context("Failed to create UpCloud server")
+					.kind(ErrorKind::Server)?
+			)
+		};
+
+		let res = signal::interruptible(async {
+			// connect to docker - install the server
+			server.install(config, repodir).await.context("Failed to install server")?;
+
+			// connect to docker
+			let docker = server.connect_to_docker().context("Failed to connect to docker")?;
+			info!("Connected to docker daemon");
+			let repomount = server.repomount(repodir);
+
+			// start our local caddy server
+			let caddy = if with_caddy {
+				docker::build_caddy(&docker, config)
+					.await
+					.context("Unable to build caddy image")?;
+				let caddy = docker::start_caddy(&docker, &repomount)
+					.await
+					.context("Unable to start caddy container")?;
+				Some(caddy)
+			} else {
+				None
+			};
+
+			Ok((docker, repomount, caddy))
+		})
+		.await;
+
+		match res {
+			Ok((docker, repomount, caddy)) => {
+				// determine the docker environment
+				debug!("Inspecting docker environment");
+				let cores = server.cores();
+				let cidr_v6 = server.cidr_v6();
+				Ok(Self {
+					server: Mutex::new(server),
+					docker: Arc::new(docker),
+					repomount,
+					cores,
+					cidr_v6,
+					caddy,
+					index_locks: Mutex::default(),
+					_guard: guard
+				})
+			},
+			Err(err) => {
+				if let Err(err) = server.destroy().await {
+					error!("Failed to destroy the server: {}", err);
+				}
+				Err(err).kind(ErrorKind::Server)
+			}
+		}
+	}
+
+	/// Rebuild the index of the current repo dir, waiting for other rebuilds of the same index to finish.
+	pub async fn rebuild_index(&self, config: &Config, merge: bool) -> anyhow::Result<()> {
+		let lock = self
+			.index_locks
+			.lock()
+			.await
+			.entry(config.alpine.repo_dir())
+			.or_default()
+			.clone();
+		let _guard = lock.lock().await;
+		build::rebuild_index(&self.repomount, &self.docker, config, merge).await
+	}
+
+	/// Make sure all changes made to the repository on the server are present in the repodir.
+	pub async fn download_repo_changes(&self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+		self.server.lock().await.download_repo_changes(config, repodir).await
+	}
+
+	/// Upload all changes made to the repository on the server.
+	pub async fn upload_repo_changes(&self, config: &Config, repodir: &Path) -> anyhow::Result<()> {
+		self.server.lock().await.upload_repo_changes(config, repodir).await
+	}
+
+	/// Remove all containers that are still running, stop the caddy container and destroy the
+	/// server. This is also used to clean up after the process was interrupted.
+	pub async fn stop(self) -> anyhow::Result<()> {
+		// remove containers that were interrupted
+		docker::remove_running_containers(&self.docker).await;
+
+		// stop the caddy container
+		if let Some(caddy) = self.caddy {
+			if let Err(err) = caddy.stop(&self.docker).await {
+				error!("Unable to stop caddy: {}", err);
+			}
+		}
+
+		// remove the server
+		self.server
+			.into_inner()
+			.destroy()
+			.await
+			.context("Failed to destroy the server")
+			.kind(ErrorKind::Server)
+	}
+}
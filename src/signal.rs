@@ -0,0 +1,111 @@
+use std::{
+	fmt::{self, Display},
+	future::Future,
+	process::exit,
+	sync::atomic::{AtomicUsize, Ordering}
+};
+use tokio::{
+	signal::unix::{signal, SignalKind},
+	sync::watch
+};
+
+type Signal = Option<(&'static str, i32)>;
+
+lazy_static! {
+	static ref CHANNEL: (watch::Sender<Signal>, watch::Receiver<Signal>) = watch::channel(None);
+}
+
+/// The error returned by [interruptible] when the process received a signal.
+#[derive(Debug)]
+pub struct Interrupted {
+	pub signal: &'static str,
+	pub signo: i32
+}
+
+impl Display for Interrupted {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Interrupted by {}", self.signal)
+	}
+}
+
+impl std::error::Error for Interrupted {}
+
+/// The amount of alive [Guard]s.
+static GUARDS: AtomicUsize = AtomicUsize::new(0);
+
+/// While a guard is alive, SIGINT and SIGTERM don't terminate the process, but make
+/// [interruptible] return an error so that the owner of the guard can clean up.
+pub struct Guard(());
+
+impl Guard {
+	pub fn new() -> Self {
+		GUARDS.fetch_add(1, Ordering::SeqCst);
+		Self(())
+	}
+}
+
+impl Drop for Guard {
+	fn drop(&mut self) {
+		GUARDS.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Install handlers for SIGINT and SIGTERM. If no [Guard] is alive or a signal was already
+/// received before, the process is terminated immediately.
+pub fn install() -> anyhow::Result<()> {
+	let mut sigint = signal(SignalKind::interrupt())?;
+	let mut sigterm = signal(SignalKind::terminate())?;
+	tokio::spawn(async move {
+		let mut received = false;
+		loop {
+			let (signal, signo) = tokio::select! {
+				_ = sigint.recv() => ("SIGINT", 2),
+				_ = sigterm.recv() => ("SIGTERM", 15)
+			};
+			if received || GUARDS.load(Ordering::SeqCst) == 0 {
+				error!("Received {}, exiting", signal);
+				exit(128 + signo);
+			}
+
+			warn!("Received {}, cleaning up - send it again to exit immediately", signal);
+			received = true;
+			if CHANNEL.0.broadcast(Some((signal, signo))).is_err() {
+				exit(128 + signo);
+			}
+		}
+	});
+	Ok(())
+}
+
+/// Return an error if the process has received a signal.
+fn check() -> anyhow::Result<()> {
+	match *CHANNEL.1.borrow() {
+		Some((signal, signo)) => Err(Interrupted { signal, signo }.into()),
+		None => Ok(())
+	}
+}
+
+/// Wait for a signal to be received.
+async fn received() -> Interrupted {
+	let mut rx = CHANNEL.1.clone();
+	loop {
+		match rx.recv().await {
+			Some(Some((signal, signo))) => return Interrupted { signal, signo },
+			Some(None) => continue,
+			None => futures_util::future::pending().await
+		}
+	}
+}
+
+/// Run the future until it completes, or return an error as soon as the process receives a
+/// signal. In the latter case, the future is dropped.
+pub async fn interruptible<F, T>(fut: F) -> anyhow::Result<T>
+where
+	F: Future<Output = anyhow::Result<T>>
+{
+	check()?;
+	tokio::select! {
+		res = fut => res,
+		interrupted = received() => Err(interrupted.into())
+	}
+}
@@ -0,0 +1,119 @@
+use crate::error::ErrorKind;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt::{self, Display},
+	io,
+	path::{Path, PathBuf}
+};
+use tokio::{fs, sync::Mutex};
+
+/// The phases a package goes through during a run.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+	Built,
+	Tested,
+	PackagesUploaded,
+	DockerBuilt,
+	DockerPushed
+}
+
+impl Phase {
+	/// Return the category of errors that occur during this phase.
+	pub fn error_kind(self) -> ErrorKind {
+		match self {
+			Self::Built | Self::DockerBuilt => ErrorKind::Build,
+			Self::Tested => ErrorKind::Test,
+			Self::PackagesUploaded | Self::DockerPushed => ErrorKind::Publish
+		}
+	}
+}
+
+impl Display for Phase {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Built => "built",
+			Self::Tested => "tested",
+			Self::PackagesUploaded => "packages_uploaded",
+			Self::DockerBuilt => "docker_built",
+			Self::DockerPushed => "docker_pushed"
+		})
+	}
+}
+
+type Packages = BTreeMap<String, BTreeSet<Phase>>;
+
+/// The phases every package has finished, persisted in a state file next to the repodir or in the
+/// working directory so that a failed run can be resumed from the first unfinished phase.
+pub struct State {
+	path: PathBuf,
+	packages: Mutex<Packages>
+}
+
+impl State {
+	/// Return the path of the state file that belongs to the repodir.
+	pub fn path(repodir: &Path) -> Option<PathBuf> {
+		let mut file_name = repodir.file_name()?.to_owned();
+		file_name.push(".state.json");
+		Some(repodir.with_file_name(file_name))
+	}
+
+	/// Load the state file, or start with an empty state if it does not exist yet.
+	pub async fn load(path: PathBuf) -> anyhow::Result<Self> {
+		let packages = match fs::read(&path).await {
+			Ok(buf) => {
+				info!("Reading state from {}", path.display());
+				serde_json::from_slice(&buf).with_context(|| format!("Invalid state file {}", path.display()))?
+			},
+			Err(err) if err.kind() == io::ErrorKind::NotFound => Packages::new(),
+			Err(err) => return Err(err).with_context(|| format!("Unable to read state file {}", path.display()))
+		};
+		Ok(Self {
+			path,
+			packages: Mutex::new(packages)
+		})
+	}
+
+	async fn save(&self, packages: &Packages) -> anyhow::Result<()> {
+		let path = &self.path;
+		let tmp = path.with_extension("tmp");
+		fs::write(&tmp, serde_json::to_vec_pretty(packages)?)
+			.await
+			.with_context(|| format!("Unable to write state file {}", tmp.display()))?;
+		fs::rename(&tmp, path)
+			.await
+			.with_context(|| format!("Unable to write state file {}", path.display()))
+	}
+
+	/// Return the phases the package has finished, or `None` if the package was never started.
+	pub async fn phases(&self, key: &str) -> Option<BTreeSet<Phase>> {
+		self.packages.lock().await.get(key).cloned()
+	}
+
+	/// Record that the package has finished a phase.
+	pub async fn finish(&self, key: &str, phase: Phase) -> anyhow::Result<()> {
+		let mut packages = self.packages.lock().await;
+		packages.entry(key.to_owned()).or_default().insert(phase);
+		self.save(&packages).await
+	}
+
+	/// Start the package from scratch, forgetting all phases it has finished.
+	pub async fn reset(&self, key: &str) -> anyhow::Result<()> {
+		let mut packages = self.packages.lock().await;
+		packages.insert(key.to_owned(), BTreeSet::new());
+		self.save(&packages).await
+	}
+
+	/// Forget all packages that are not contained in `keys`.
+	pub async fn retain(&self, keys: &BTreeSet<String>) -> anyhow::Result<()> {
+		let mut packages = self.packages.lock().await;
+		let len = packages.len();
+		packages.retain(|key, _| keys.contains(key));
+		if packages.len() == len {
+			return Ok(());
+		}
+		self.save(&packages).await
+	}
+}
@@ -1,7 +1,12 @@
-use crate::{build::packages::Package, config::*, docker::IPv6CIDR};
+use crate::{build::packages::Package, config::*, docker::IPv6CIDR, repo::REMOTE_INDEX};
+use anyhow::{anyhow, bail};
 use askama::Template;
 use chrono::NaiveDate;
-use std::fmt::{self, Display};
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	fmt::{self, Display}
+};
 
 const GIT_COMMIT: &str = env!("GIT_COMMIT");
 
@@ -15,30 +20,153 @@ impl Display for Rustver {
 	}
 }
 
+/// The variables that are available in every custom template, in addition to the ones from the
+/// config file.
+pub const CUSTOM_TEMPLATE_VARIABLES: &[&str] = &["alpine", "arch", "pubkey", "pkgname", "pkgver", "pkgrel", "git_commit"];
+
+#[derive(Clone, Debug)]
+enum Segment {
+	Literal(String),
+	Variable(String)
+}
+
+/// A template that is read from disk at runtime. Unlike our compiled templates, it only supports
+/// substituting variables like `{{ pkgver }}`.
+#[derive(Clone, Debug, Default)]
+pub struct CustomTemplate {
+	segments: Vec<Segment>
+}
+
+impl CustomTemplate {
+	pub fn parse(mut src: &str) -> anyhow::Result<Self> {
+		let mut segments = Vec::new();
+		while let Some(start) = src.find("{{") {
+			segments.push(Segment::Literal(src[..start].to_owned()));
+			src = &src[start + 2..];
+			let end = src.find("}}").ok_or_else(|| anyhow!("Unterminated variable in template"))?;
+			let name = src[..end].trim();
+			if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+				bail!("Invalid variable name {:?} in template", name);
+			}
+			segments.push(Segment::Variable(name.to_owned()));
+			src = &src[end + 2..];
+		}
+		segments.push(Segment::Literal(src.to_owned()));
+		Ok(Self { segments })
+	}
+
+	/// Return the names of all variables that are used by this template.
+	pub fn variables(&self) -> impl Iterator<Item = &str> {
+		self.segments.iter().filter_map(|segment| match segment {
+			Segment::Literal(_) => None,
+			Segment::Variable(name) => Some(name.as_str())
+		})
+	}
+}
+
+struct CustomTemplateRenderer<'t> {
+	template: &'t CustomTemplate,
+	variables: HashMap<&'t str, Cow<'t, str>>
+}
+
+impl Template for CustomTemplateRenderer<'_> {
+	fn render_into(&self, writer: &mut dyn fmt::Write) -> askama::Result<()> {
+		for segment in &self.template.segments {
+			match segment {
+				Segment::Literal(literal) => writer.write_str(literal)?,
+				// the variables were checked when the template was loaded
+				Segment::Variable(name) => writer.write_str(self.variables.get(name.as_str()).ok_or(fmt::Error)?)?
+			}
+		}
+		Ok(())
+	}
+
+	fn extension(&self) -> Option<&'static str> {
+		None
+	}
+
+	fn size_hint(&self) -> usize {
+		self.template
+			.segments
+			.iter()
+			.map(|segment| match segment {
+				Segment::Literal(literal) => literal.len(),
+				Segment::Variable(_) => 0
+			})
+			.sum()
+	}
+}
+
 impl Config {
 	pub fn index_html<'a>(&'a self) -> impl Template + 'a {
 		#[derive(Template)]
 		#[template(path = "index.html")]
 		struct IndexHtmlTemplate<'t> {
 			alpine: &'t str,
-			pubkey: &'t str
+			pubkey: &'t str,
+			keep: Option<usize>,
+			repo_url: String,
+			github: &'t str
 		}
 
 		IndexHtmlTemplate {
 			alpine: &self.alpine.version,
-			pubkey: &self.alpine.pubkey
+			pubkey: &self.alpine.pubkey,
+			keep: self.retention.rust,
+			repo_url: self.deploy.repo.url(),
+			github: &self.deploy.github.repo
+		}
+	}
+
+	/// Render the Dockerfile that rebuilds the index using [Config::index_script].
+	pub fn index_dockerfile<'a>(&'a self) -> impl Template + 'a {
+		#[derive(Template)]
+		#[template(path = "index.Dockerfile")]
+		struct IndexDockerfile<'t> {
+			alpine: &'t str,
+			privkey: &'t str
+		}
+
+		IndexDockerfile {
+			alpine: &self.alpine.version,
+			privkey: &self.alpine.privkey
+		}
+	}
+
+	/// Render the script that rebuilds the index. If `merge` is set, the packages of the remote
+	/// index that are missing from the repodir are kept.
+	pub fn index_script<'a>(&'a self, merge: bool) -> impl Template + 'a {
+		#[derive(Template)]
+		#[template(path = "index.sh")]
+		struct IndexScript<'t> {
+			arch: &'t str,
+			repo_dir: String,
+			merge: bool,
+			remote_index: &'t str
+		}
+
+		IndexScript {
+			arch: &self.alpine.arch,
+			repo_dir: self.alpine.repo_dir(),
+			merge,
+			remote_index: REMOTE_INDEX
 		}
 	}
 
 	pub fn caddyfile<'a>(&'a self) -> impl Template + 'a {
 		#[derive(Template)]
 		#[template(path = "caddy/Caddyfile")]
-		struct Caddyfile<'t> {
-			alpine: &'t str
+		struct Caddyfile {
+			origin: String,
+			path: String,
+			host: String
 		}
 
+		let (origin, path) = self.deploy.repo.url_parts();
 		Caddyfile {
-			alpine: &self.alpine.version
+			origin,
+			path,
+			host: self.deploy.repo.host()
 		}
 	}
 
@@ -50,21 +178,23 @@ impl Config {
 		Dockerfile
 	}
 
-	pub fn packages_dockerfile_abuild<'a>(&'a self, jobs: u16) -> impl Template + 'a {
+	pub fn packages_dockerfile_abuild<'a>(&'a self, jobs: u16, files: &'a [LocalFile]) -> impl Template + 'a {
 		#[derive(Template)]
 		#[template(path = "packages/abuild.Dockerfile")]
 		struct DockerfileAbuild<'t> {
 			alpine: &'t str,
 			pubkey: &'t str,
 			privkey: &'t str,
-			jobs: u16
+			jobs: u16,
+			files: &'t [LocalFile]
 		}
 
 		DockerfileAbuild {
 			alpine: &self.alpine.version,
 			pubkey: &self.alpine.pubkey,
 			privkey: &self.alpine.privkey,
-			jobs
+			jobs,
+			files
 		}
 	}
 
@@ -90,6 +220,7 @@ impl Config {
 		#[derive(Template)]
 		#[template(path = "packages/crate.APKBUILD")]
 		struct CrateApkbuild<'t> {
+			arch: &'t str,
 			crate_name: &'t str,
 			version: &'t str,
 			pkgrel: u32,
@@ -97,10 +228,28 @@ impl Config {
 			license: &'t str,
 			check: bool,
 			dependencies: &'t [String],
-			sha512sum: &'t str
+			sha512sum: &'t str,
+			git: Option<GitSource<'t>>,
+			cargo_flags: String,
+			install_flags: String
+		}
+
+		struct GitSource<'t> {
+			url: &'t str,
+			rev: &'t str
+		}
+
+		let mut cargo_flags = String::new();
+		if krate.no_default_features {
+			cargo_flags += " --no-default-features";
+		}
+		if !krate.features.is_empty() {
+			cargo_flags += &format!(" --features {}", krate.features.join(","));
 		}
+		let install_flags = krate.bins.iter().map(|bin| format!(" --bin {}", bin)).collect();
 
 		CrateApkbuild {
+			arch: &self.alpine.arch,
 			crate_name: &krate.crate_name,
 			version: &krate.version,
 			pkgrel: krate.pkgrel,
@@ -108,7 +257,10 @@ impl Config {
 			license: &krate.license,
 			check: krate.check,
 			dependencies: &krate.dependencies,
-			sha512sum: &krate.sha512sum
+			sha512sum: &krate.sha512sum,
+			git: krate.git.as_deref().zip(krate.rev.as_deref()).map(|(url, rev)| GitSource { url, rev }),
+			cargo_flags,
+			install_flags
 		}
 	}
 
@@ -120,6 +272,9 @@ impl Config {
 			pubkey: &'t str,
 			crate_name: &'t str,
 			pkgname: String,
+			image: String,
+			repo_url: String,
+			github: &'t str,
 			git_commit: &'t str
 		}
 
@@ -128,10 +283,45 @@ impl Config {
 			pubkey: &self.alpine.pubkey,
 			crate_name: &krate.crate_name,
 			pkgname: krate.pkgname(),
+			image: self.deploy.registry.image(&format!("alpine-{}", krate.pkgname())),
+			repo_url: self.deploy.repo.url(),
+			github: &self.deploy.github.repo,
 			git_commit: GIT_COMMIT
 		}
 	}
 
+	fn package_custom_template<'a>(&'a self, custom: &'a PackageCustom, template: &'a CustomTemplate) -> impl Template + 'a {
+		let mut variables: HashMap<&str, Cow<'_, str>> = custom
+			.vars
+			.iter()
+			.map(|(name, value)| {
+				let value = match value {
+					toml::Value::String(value) => value.into(),
+					value => value.to_string().into()
+				};
+				(name.as_str(), value)
+			})
+			.collect();
+		variables.insert("alpine", self.alpine.version.as_str().into());
+		variables.insert("arch", self.alpine.arch.as_str().into());
+		variables.insert("pubkey", self.alpine.pubkey.as_str().into());
+		variables.insert("pkgname", custom.pkgname.as_str().into());
+		variables.insert("pkgver", custom.pkgver.as_str().into());
+		variables.insert("pkgrel", custom.pkgrel.to_string().into());
+		variables.insert("git_commit", GIT_COMMIT.into());
+
+		CustomTemplateRenderer { template, variables }
+	}
+
+	pub fn package_custom_apkbuild<'a>(&'a self, custom: &'a PackageCustom) -> impl Template + 'a {
+		self.package_custom_template(custom, &custom.apkbuild_template)
+	}
+
+	pub fn package_custom_dockerfile<'a>(&'a self, custom: &'a PackageCustom) -> Option<impl Template + 'a> {
+		let template = custom.dockerfile_template.as_ref()?;
+		Some(self.package_custom_template(custom, template))
+	}
+
 	pub fn rust_dockerfile_abuild<'a>(&'a self, channel: &str, jobs: u16) -> impl Template + 'a {
 		#[derive(Template)]
 		#[template(path = "rust/abuild.Dockerfile")]
@@ -140,15 +330,18 @@ impl Config {
 			pubkey: &'t str,
 			privkey: &'t str,
 			sysver: Option<&'t str>,
-			jobs: u16
+			jobs: u16,
+			patches: bool
 		}
 
+		let rust = &self.rust[channel];
 		DockerfileAbuild {
 			alpine: &self.alpine.version,
 			pubkey: &self.alpine.pubkey,
 			privkey: &self.alpine.privkey,
-			sysver: self.rust[channel].sysver.as_deref(),
-			jobs
+			sysver: rust.sysver.as_deref(),
+			jobs,
+			patches: !self.patches.local(&rust.rustver()).unwrap_or_default().is_empty()
 		}
 	}
 
@@ -159,6 +352,9 @@ impl Config {
 			alpine: &'t str,
 			pubkey: &'t str,
 			channel: &'t str,
+			image: String,
+			repo_url: String,
+			github: &'t str,
 			git_commit: &'t str
 		}
 
@@ -166,6 +362,9 @@ impl Config {
 			alpine: &self.alpine.version,
 			pubkey: &self.alpine.pubkey,
 			channel,
+			image: self.deploy.registry.image("alpine-rust"),
+			repo_url: self.deploy.repo.url(),
+			github: &self.deploy.github.repo,
 			git_commit: GIT_COMMIT
 		}
 	}
@@ -177,6 +376,9 @@ impl Config {
 			alpine: &'t str,
 			pubkey: &'t str,
 			channel: &'t str,
+			image: String,
+			repo_url: String,
+			github: &'t str,
 			git_commit: &'t str
 		}
 
@@ -184,6 +386,9 @@ impl Config {
 			alpine: &self.alpine.version,
 			pubkey: &self.alpine.pubkey,
 			channel,
+			image: self.deploy.registry.image("alpine-rust"),
+			repo_url: self.deploy.repo.url(),
+			github: &self.deploy.github.repo,
 			git_commit: GIT_COMMIT
 		}
 	}
@@ -208,6 +413,7 @@ impl Config {
 		#[derive(Template)]
 		#[template(path = "rust/APKBUILD")]
 		struct ApkbuildTemplate<'t> {
+			arch: &'t str,
 			channel: &'t str,
 			pkgver: &'t str,
 			pkgrel: u32,
@@ -216,11 +422,14 @@ impl Config {
 			bootver: &'t str,
 			bootsys: bool,
 			python: Option<&'t str>,
-			sha512sums: &'t str
+			sha512sums: &'t str,
+			github: &'t str,
+			patches: Option<&'t [LocalFile]>
 		}
 
 		let rust: &'a Rust = &self.rust[channel];
 		ApkbuildTemplate {
+			arch: &self.alpine.arch,
 			channel,
 			pkgver: &rust.pkgver,
 			pkgrel: rust.pkgrel,
@@ -229,7 +438,70 @@ impl Config {
 			bootver: &rust.bootver,
 			bootsys: rust.bootsys,
 			python: rust.python.as_deref(),
-			sha512sums: &rust.sha512sums
+			sha512sums: &rust.sha512sums,
+			github: &self.deploy.github.repo,
+			patches: self.patches.local(&rust.rustver())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn render(src: &str, variables: &[(&'static str, &'static str)]) -> String {
+		let template = CustomTemplate::parse(src).unwrap();
+		let variables = variables.iter().map(|(name, value)| (*name, Cow::Borrowed(*value))).collect();
+		CustomTemplateRenderer {
+			template: &template,
+			variables
 		}
+		.render()
+		.unwrap()
+	}
+
+	#[test]
+	fn parse_variables() {
+		let template = CustomTemplate::parse("pkgname={{pkgname}}\npkgver={{ pkgver }}\n").unwrap();
+		assert_eq!(template.variables().collect::<Vec<_>>(), vec!["pkgname", "pkgver"]);
+		assert_eq!(CustomTemplate::parse("no variables").unwrap().variables().count(), 0);
+	}
+
+	#[test]
+	fn parse_unterminated() {
+		let err = CustomTemplate::parse("pkgname={{pkgname\n").unwrap_err();
+		assert_eq!(err.to_string(), "Unterminated variable in template");
+		assert!(CustomTemplate::parse("{{pkgname}} {{").is_err());
+	}
+
+	#[test]
+	fn parse_invalid_name() {
+		assert!(CustomTemplate::parse("{{}}").is_err());
+		assert!(CustomTemplate::parse("{{ pkg-name }}").is_err());
+	}
+
+	#[test]
+	fn render_variables() {
+		let rendered = render("{{pkgname}}-{{ pkgver }}: ${pkgname} {{pkgname}}", &[("pkgname", "foo"), ("pkgver", "1.0")]);
+		assert_eq!(rendered, "foo-1.0: ${pkgname} foo");
+	}
+
+	#[test]
+	fn render_git_crate() {
+		let config: Config = toml::from_str(
+			"[alpine]\nversions = [\"3.13\"]\npubkey = \"key.rsa.pub\"\nprivkey = \"key.rsa\"\nllvm = [10]\n\
+			 [[packages.crate]]\ncrate_name = \"foo\"\nversion = \"1.0.0\"\npkgrel = 0\ndescription = \"Foo\"\n\
+			 license = \"MIT\"\ndependencies = []\ngit = \"https://example.com/foo.git\"\nrev = \"v1.0.0\"\n\
+			 features = [\"a\", \"b\"]\nbins = [\"foo\", \"bar\"]\n"
+		)
+		.unwrap();
+		let config = config.for_alpine("3.13", "x86_64");
+		let apkbuild = config.package_crate_apkbuild(&config.packages.crates[0]).render().unwrap();
+		assert!(apkbuild.contains("url=\"https://example.com/foo.git\"\n"));
+		assert!(apkbuild.contains("git clone \"https://example.com/foo.git\" \"$builddir\"\n"));
+		assert!(apkbuild.contains("git -C \"$builddir\" checkout \"v1.0.0\"\n"));
+		assert!(apkbuild.contains("cargo build $_locked --features a,b --workspace --release\n"));
+		assert!(apkbuild.contains("cargo install $_locked --features a,b --bin foo --bin bar --path . "));
+		assert!(!apkbuild.contains("crates.io"));
 	}
 }
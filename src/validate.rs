@@ -0,0 +1,205 @@
+use crate::{
+	build::packages::Package,
+	config::{Config, PackageCrate, StorageKind},
+	error::{ErrorKind, ErrorKindExt},
+	graph::BuildGraph
+};
+use anyhow::anyhow;
+use itertools::Itertools;
+use openssl::pkey::PKey;
+use regex::Regex;
+use tokio::fs;
+
+lazy_static! {
+	static ref SHA512SUM_REGEX: Regex = Regex::new(r"^[0-9a-f]{128}  [^\s]+$").unwrap();
+	static ref VERSION_REGEX: Regex = Regex::new(r"^\d+\.\d+(\.\d+)?$").unwrap();
+	static ref SYSVER_REGEX: Regex = Regex::new(r"^(\d+\.\d+|edge)$").unwrap();
+}
+
+/// Check that every line is a well-formed output line of `sha512sum`.
+fn check_sha512sums(problems: &mut Vec<String>, what: &str, sha512sums: &str) {
+	let mut lines = sha512sums.lines().filter(|line| !line.trim().is_empty()).peekable();
+	if lines.peek().is_none() {
+		problems.push(format!("{} has no sha512sums", what));
+	}
+	for line in lines {
+		if !SHA512SUM_REGEX.is_match(line) {
+			problems.push(format!("{} has a malformed sha512sums line: {:?}", what, line));
+		}
+	}
+}
+
+fn check_rust(problems: &mut Vec<String>, config: &Config) {
+	let llvm_pkgnames = config
+		.packages
+		.llvm
+		.iter()
+		.filter_map(|llvm| llvm.pkgver.split('.').next())
+		.collect::<Vec<_>>();
+
+	for (channel, rust) in config.rust.iter().sorted_by_key(|(channel, _)| channel.as_str()) {
+		let what = format!("Rust {}", channel);
+
+		if rust.bootsys {
+			if !VERSION_REGEX.is_match(&rust.bootver) {
+				problems.push(format!(
+					"{} bootstraps from the system rust, but bootver {:?} is not a version",
+					what, rust.bootver
+				));
+			}
+			match &rust.sysver {
+				Some(sysver) if !SYSVER_REGEX.is_match(sysver) => {
+					problems.push(format!("{} has an invalid sysver {:?}", what, sysver))
+				},
+				_ => {}
+			}
+		} else {
+			if rust.sysver.is_some() {
+				problems.push(format!("{} sets sysver, which is only used with bootsys", what));
+			}
+			if &rust.bootver == channel {
+				problems.push(format!("{} bootstraps from itself", what));
+			} else if !config.rust.contains_key(&rust.bootver) {
+				problems.push(format!(
+					"{} bootstraps from Rust {} which does not exist in the config",
+					what, rust.bootver
+				));
+			}
+		}
+
+		let llvmver = rust.llvmver.to_string();
+		if !llvm_pkgnames.contains(&llvmver.as_str()) && !config.alpine.llvm.contains(&rust.llvmver) {
+			problems.push(format!(
+				"{} requires LLVM {} which neither exists in the config nor is provided by Alpine {}",
+				what, rust.llvmver, config.alpine.version
+			));
+		}
+
+		check_sha512sums(problems, &what, &rust.sha512sums);
+		if let Some(patches) = config.patches.local(&rust.rustver()) {
+			if patches.is_empty() {
+				problems.push(format!("{} has no patches in the patch directory", what));
+			}
+			if rust.sha512sums.contains("rustc-patches-") {
+				problems.push(format!("{} uses local patches, but its sha512sums contain the patches from GitHub", what));
+			}
+		}
+	}
+}
+
+fn check_crate(problems: &mut Vec<String>, krate: &PackageCrate) {
+	let what = format!("Crate {}", krate.crate_name);
+	match (&krate.git, krate.rev.as_deref()) {
+		(Some(_), Some("HEAD")) => problems.push(format!("{} must specify a commit, tag or branch instead of HEAD", what)),
+		(Some(_), Some(_)) => {},
+		(Some(_), None) => problems.push(format!("{} is built from git but does not specify a rev", what)),
+		(None, Some(_)) => problems.push(format!("{} specifies a rev but is not built from git", what)),
+		(None, None) => check_sha512sums(problems, &what, &krate.sha512sum)
+	}
+}
+
+/// Check that the names of the custom packages are unique, since they are referenced by name.
+fn check_custom(problems: &mut Vec<String>, config: &Config) {
+	let names = config
+		.packages
+		.llvm
+		.iter()
+		.map(|llvm| llvm.pkgname())
+		.chain(config.rust.keys().cloned())
+		.chain(config.packages.crates.iter().map(|krate| krate.pkgname()))
+		.chain(config.packages.custom.iter().map(|custom| custom.pkgname.clone()))
+		.collect::<Vec<_>>();
+	for custom in &config.packages.custom {
+		if names.iter().filter(|name| **name == custom.pkgname).count() > 1 {
+			problems.push(format!("Package {} has the same name as another package", custom.pkgname));
+		}
+	}
+}
+
+/// Check that the selected storage is configured and that the repository has a public url.
+fn check_storage(problems: &mut Vec<String>, config: &Config) {
+	let repo = &config.deploy.repo;
+	let (table, set) = match repo.storage {
+		StorageKind::S3 => return,
+		StorageKind::Local => ("local", repo.local.is_some()),
+		StorageKind::Sftp => ("sftp", repo.sftp.is_some())
+	};
+	if !set {
+		problems.push(format!("deploy.repo.{} must be set when using {} storage", table, table));
+	}
+	if repo.url.is_none() {
+		problems.push(format!("deploy.repo.url must be set when using {} storage", table));
+	}
+}
+
+async fn check_keys(problems: &mut Vec<String>, config: &Config) {
+	let pubkey = match fs::read(&config.alpine.pubkey).await {
+		Ok(pem) => match PKey::public_key_from_pem(&pem) {
+			Ok(pubkey) => Some(pubkey),
+			Err(err) => {
+				problems.push(format!("Invalid public key {}: {}", config.alpine.pubkey, err));
+				None
+			}
+		},
+		Err(err) => {
+			problems.push(format!("Unable to read public key {}: {}", config.alpine.pubkey, err));
+			None
+		}
+	};
+	let privkey = match fs::read(&config.alpine.privkey).await {
+		Ok(pem) => match PKey::private_key_from_pem(&pem) {
+			Ok(privkey) => Some(privkey),
+			Err(err) => {
+				problems.push(format!("Invalid private key {}: {}", config.alpine.privkey, err));
+				None
+			}
+		},
+		Err(err) => {
+			problems.push(format!("Unable to read private key {}: {}", config.alpine.privkey, err));
+			None
+		}
+	};
+
+	if let (Some(pubkey), Some(privkey)) = (pubkey, privkey) {
+		if !pubkey.public_eq(&privkey) {
+			problems.push(format!(
+				"The private key {} does not belong to the public key {}",
+				config.alpine.privkey, config.alpine.pubkey
+			));
+		}
+	}
+}
+
+/// Check the config for mistakes that would otherwise only show up while building the packages.
+/// All problems are logged, and an error is returned if there were any.
+pub async fn validate(config: &Config) -> anyhow::Result<()> {
+	info!("Validating config");
+	let mut problems = Vec::new();
+
+	check_rust(&mut problems, config);
+	for llvm in &config.packages.llvm {
+		check_sha512sums(&mut problems, &format!("LLVM {}", llvm.pkgver), &llvm.sha512sum);
+	}
+	for krate in &config.packages.crates {
+		check_crate(&mut problems, krate);
+	}
+	check_custom(&mut problems, config);
+	check_storage(&mut problems, config);
+	check_keys(&mut problems, config).await;
+
+	// the remaining dependency problems are dependency cycles
+	if problems.is_empty() {
+		if let Err(err) = BuildGraph::new(config) {
+			problems.push(err.to_string());
+		}
+	}
+
+	if problems.is_empty() {
+		info!("The config is valid");
+		return Ok(());
+	}
+	for problem in &problems {
+		error!("{}", problem);
+	}
+	Err(anyhow!("Invalid config: {} problem(s) found", problems.len())).kind(ErrorKind::Config)
+}
@@ -0,0 +1,43 @@
+#!/bin/ash
+set -euo pipefail
+
+# rebuild the index from the packages in the repo dir and sign it
+cd /repo/{{ repo_dir }}
+index=$(mktemp -p . .APKINDEX.XXXXXX)
+placeholders=$(mktemp -d)
+trap 'rm -rf "$index" "$placeholders"' EXIT
+
+# count the packages listed in an index
+count() {
+	tar -xzOf "$1" APKINDEX | awk '/^P:/ { n++ } END { print n + 0 }'
+}
+
+{%- if merge %}
+
+# the packages that were never downloaded are represented by empty placeholders with the size from the remote
+# index and an old modification time, which makes apk index copy their entries from the remote index
+tar -xzOf {{ remote_index }} APKINDEX >"$placeholders/APKINDEX"
+awk -F: '/^P:/ { p = $2 } /^V:/ { v = $2 } /^S:/ { s = $2 } /^$/ { print p "-" v ".apk " s }' "$placeholders/APKINDEX" \
+	| while read -r file size; do
+		if [ ! -e "$file" ]; then
+			truncate -s "$size" "$placeholders/$file"
+			touch -t 200001010000 "$placeholders/$file"
+		fi
+	done
+apk index --index {{ remote_index }} --rewrite-arch {{ arch }} -o "$index" \
+	$(find . "$placeholders" -maxdepth 1 -name '*.apk')
+
+# never upload an index that lost packages of the remote index
+remote=$(count {{ remote_index }})
+merged=$(count "$index")
+if [ "$merged" -lt "$remote" ]; then
+	echo "The merged index lists $merged packages, but the remote index lists $remote" >&2
+	exit 1
+fi
+{%- else %}
+apk index --rewrite-arch {{ arch }} -o "$index" $(find . -maxdepth 1 -name '*.apk')
+{%- endif %}
+
+abuild-sign "$index"
+chmod 644 "$index"
+mv "$index" APKINDEX.tar.gz